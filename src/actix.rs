@@ -0,0 +1,230 @@
+//! Actix Web integration, with the same span fields as the Axum and `tower` layers.
+
+use crate::http::{ServerRequest, make_server_span};
+use actix_web::{
+    Error,
+    body::{BodySize, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    http::{Version, header},
+};
+use futures_util::future::LocalBoxFuture;
+use std::future::{Ready, ready};
+use tracing::{Instrument, Span};
+use tracing_datadog::{
+    context::{TraceContextExt, TracingContextExt},
+    http::W3CTraceContextHeaders,
+};
+
+/// Actix Web middleware to create spans for requests.
+///
+/// Spans have the same fields as those of `axum::AxumTraceLayer`, with the resource and
+/// `http.route` taken from the matched resource pattern. The trace is continued from the W3C
+/// trace context headers, and the span records the response status and server errors.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{App, web};
+/// use komoju_datadog::actix::ActixTraceMiddleware;
+///
+/// let app = App::new()
+///     .wrap(ActixTraceMiddleware)
+///     .route("/merchants/{id}", web::get().to(get_merchant));
+///
+/// async fn get_merchant(id: web::Path<String>) -> String {
+///     id.into_inner()
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ActixTraceMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for ActixTraceMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ActixTraceService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ActixTraceService { service }))
+    }
+}
+
+/// Middleware `Service` that creates spans for every request, see [`ActixTraceMiddleware`].
+#[derive(Debug)]
+pub struct ActixTraceService<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ActixTraceService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let span = make_span_from_request(&req);
+        let future = {
+            let _guard = span.enter();
+            self.service.call(req)
+        };
+
+        Box::pin(async move {
+            let result = future.instrument(span.clone()).await;
+            match &result {
+                Ok(response) => update_span_from_response(&span, response),
+                Err(err) => update_span_from_error(&span, err),
+            }
+            result
+        })
+    }
+}
+
+/// Creates a span from a request.
+fn make_span_from_request(req: &ServiceRequest) -> Span {
+    let header = |name: &str| req.headers().get(name).and_then(|h| h.to_str().ok());
+    let uri = req.uri();
+    let span = make_server_span(&ServerRequest {
+        operation: "actix.request",
+        method: req.method().as_str(),
+        path: uri.path(),
+        host: header(header::HOST.as_str()),
+        uri_host: uri.host(),
+        scheme: uri.scheme_str(),
+        user_agent: header(header::USER_AGENT.as_str()),
+        client_ip: header("x-forwarded-for")
+            .map(str::to_string)
+            .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string())),
+        request_id: header("x-request-id"),
+        content_length: header(header::CONTENT_LENGTH.as_str()).and_then(|h| h.parse().ok()),
+        protocol_version: match req.version() {
+            Version::HTTP_10 => "1.0",
+            Version::HTTP_11 => "1.1",
+            Version::HTTP_2 => "2.0",
+            Version::HTTP_3 => "3.0",
+            _ => "",
+        },
+    });
+
+    if let Some(route) = req.match_pattern() {
+        span.record("resource", format!("{} {route}", req.method()));
+        span.record("http.route", route);
+    }
+
+    // Actix uses its own `http` version, so copy the trace context headers over.
+    let mut headers = http::HeaderMap::new();
+    for name in ["traceparent", "tracestate"] {
+        if let Some(value) = req
+            .headers()
+            .get(name)
+            .and_then(|h| http::HeaderValue::from_bytes(h.as_bytes()).ok())
+        {
+            headers.insert(name, value);
+        }
+    }
+    span.set_context(headers.extract_trace_context::<W3CTraceContextHeaders>());
+
+    span
+}
+
+/// Updates a span with tags from the response.
+///
+/// Responses created from server errors are recorded as errors, while client errors only record
+/// `http.rejection.message`, like extractor rejections in Axum.
+fn update_span_from_response<B: MessageBody>(span: &Span, response: &ServiceResponse<B>) {
+    let status = response.status();
+    span.record("http.status_code", status.as_u16());
+    if let BodySize::Sized(content_length) = response.response().body().size() {
+        span.record("http.response.content_length", content_length);
+    }
+
+    if let Some(err) = response.response().error() {
+        if status.is_server_error() {
+            crate::tracing::record_error(span, err);
+        } else if status.is_client_error() {
+            span.record("http.rejection.message", err.to_string());
+        }
+    }
+}
+
+/// Updates a span with tags from an error that was not turned into a response yet.
+fn update_span_from_error(span: &Span, err: &Error) {
+    let status = err.as_response_error().status_code();
+    span.record("http.status_code", status.as_u16());
+    if status.is_client_error() {
+        span.record("http.rejection.message", err.to_string());
+    } else {
+        crate::tracing::record_error(span, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::with_recorder;
+    use actix_web::{App, HttpResponse, error, test, web};
+
+    #[test]
+    fn spans_record_route_and_status() {
+        let (recorder, _) = with_recorder(async {
+            let app = test::init_service(App::new().wrap(ActixTraceMiddleware).route(
+                "/merchants/{id}",
+                web::get().to(|| async { HttpResponse::Ok().body("ok") }),
+            ))
+            .await;
+            let request = test::TestRequest::get()
+                .uri("/merchants/abc123")
+                .insert_header(("X-Request-Id", "req-1"))
+                .to_request();
+            let _ = test::call_service(&app, request).await;
+        });
+
+        assert_eq!(recorder.get("operation").as_deref(), Some("actix.request"));
+        assert_eq!(
+            recorder.get("resource").as_deref(),
+            Some("GET /merchants/{id}")
+        );
+        assert_eq!(
+            recorder.get("http.route").as_deref(),
+            Some("/merchants/{id}")
+        );
+        assert_eq!(recorder.get("http.request_id").as_deref(), Some("req-1"));
+        assert_eq!(recorder.get("http.status_code").as_deref(), Some("200"));
+        assert_eq!(
+            recorder.get("http.response.content_length").as_deref(),
+            Some("2")
+        );
+    }
+
+    #[test]
+    fn server_errors_are_recorded() {
+        let (recorder, _) = with_recorder(async {
+            let app = test::init_service(App::new().wrap(ActixTraceMiddleware).route(
+                "/",
+                web::get().to(|| async {
+                    Err::<HttpResponse, _>(error::ErrorInternalServerError("database is down"))
+                }),
+            ))
+            .await;
+            let _ = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        });
+
+        assert_eq!(recorder.get("http.status_code").as_deref(), Some("500"));
+        assert_eq!(
+            recorder.get("error.message").as_deref(),
+            Some("database is down")
+        );
+        assert_eq!(recorder.get("http.rejection.message"), None);
+    }
+}
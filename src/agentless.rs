@@ -0,0 +1,168 @@
+//! Agentless mode, sending logs, metrics and traces directly to Datadog's intake.
+//!
+//! All are buffered and submitted by background threads, so that callers never block on HTTP
+//! requests.
+
+use std::time::Duration;
+
+mod logs;
+mod metrics;
+mod traces;
+
+pub(crate) use logs::LogLayer;
+pub(crate) use metrics::MetricsAggregator;
+pub(crate) use traces::relay_traces;
+
+/// Timeout for requests to the intake.
+const INTAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The endpoints of Datadog's intake for a site.
+#[derive(Clone, Debug)]
+pub(crate) struct Intake {
+    api_key: String,
+    logs_url: String,
+    series_url: String,
+    distribution_points_url: String,
+    traces_url: String,
+}
+
+impl Intake {
+    /// Returns the intake of a Datadog site, e.g. `datadoghq.com`.
+    pub(crate) fn new(api_key: &str, site: &str) -> Self {
+        Self {
+            api_key: api_key.to_string(),
+            logs_url: format!("https://http-intake.logs.{site}/api/v2/logs"),
+            series_url: format!("https://api.{site}/api/v2/series"),
+            distribution_points_url: format!("https://api.{site}/api/v1/distribution_points"),
+            traces_url: format!("https://trace.agent.{site}/api/v0.2/traces"),
+        }
+    }
+
+    /// Returns a client for the intake, to be used from a background thread.
+    fn client() -> reqwest::blocking::Client {
+        reqwest::blocking::Client::builder()
+            .timeout(INTAKE_TIMEOUT)
+            .build()
+            .expect("failed to build Datadog intake client")
+    }
+
+    /// Submits a JSON payload, logging failures.
+    fn post(&self, client: &reqwest::blocking::Client, url: &str, payload: &serde_json::Value) {
+        self.post_with_headers(client, url, &[], payload);
+    }
+
+    /// Submits a JSON payload with additional headers, logging failures.
+    fn post_with_headers(
+        &self,
+        client: &reqwest::blocking::Client,
+        url: &str,
+        headers: &[(&str, &str)],
+        payload: &serde_json::Value,
+    ) {
+        let mut request = client.post(url).header("DD-API-KEY", &self.api_key);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let result = request
+            .json(payload)
+            .send()
+            .and_then(reqwest::blocking::Response::error_for_status);
+        if let Err(error) = result {
+            tracing::warn!(%error, url, "failed to submit to Datadog intake");
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    /// A request received by [`stand_in_intake`].
+    pub(crate) struct IntakeRequest {
+        pub(crate) path: String,
+        /// The headers, by lowercase name.
+        pub(crate) headers: HashMap<String, String>,
+        pub(crate) body: serde_json::Value,
+    }
+
+    impl IntakeRequest {
+        /// Returns the value of a header, by lowercase name.
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(name).map(String::as_str)
+        }
+    }
+
+    /// Starts a local stand-in for Datadog's intake, returning its endpoints and the requests it
+    /// receives.
+    pub(crate) fn stand_in_intake() -> (Intake, mpsc::Receiver<IntakeRequest>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap().to_string();
+
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(": ") else {
+                        break;
+                    };
+                    headers.insert(name.to_ascii_lowercase(), value.to_string());
+                }
+
+                let content_length = headers
+                    .get("content-length")
+                    .map_or(0, |len| len.parse().unwrap());
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 202 Accepted\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+
+                let body = serde_json::from_slice(&body).unwrap();
+                let _ = sender.send(IntakeRequest {
+                    path,
+                    headers,
+                    body,
+                });
+            }
+        });
+
+        let intake = Intake {
+            api_key: String::from("test-api-key"),
+            logs_url: format!("{base_url}/api/v2/logs"),
+            series_url: format!("{base_url}/api/v2/series"),
+            distribution_points_url: format!("{base_url}/api/v1/distribution_points"),
+            traces_url: format!("{base_url}/api/v0.2/traces"),
+        };
+        (intake, receiver)
+    }
+
+    #[test]
+    fn intake_urls_for_site() {
+        let intake = Intake::new("key", "datadoghq.eu");
+        assert_eq!(
+            intake.logs_url,
+            "https://http-intake.logs.datadoghq.eu/api/v2/logs"
+        );
+        assert_eq!(intake.series_url, "https://api.datadoghq.eu/api/v2/series");
+        assert_eq!(
+            intake.traces_url,
+            "https://trace.agent.datadoghq.eu/api/v0.2/traces"
+        );
+    }
+}
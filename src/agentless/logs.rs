@@ -0,0 +1,240 @@
+//! Log shipping to Datadog's logs intake.
+
+use super::Intake;
+use serde_json::{Map, Value, json};
+use std::{
+    cell::Cell,
+    fmt::Debug,
+    sync::mpsc::{self, RecvTimeoutError, SyncSender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_datadog::context::TracingContextExt;
+use tracing_subscriber::{Layer, layer::Context};
+
+/// How often buffered logs are submitted.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum number of logs per request, as accepted by the intake.
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// The maximum number of logs waiting to be submitted, beyond which logs are dropped.
+const QUEUE_SIZE: usize = 10_000;
+
+/// How long [`LogLayer::flush`] waits for pending logs to be submitted.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// Whether the current thread is the one submitting logs, whose own events must not be
+    /// shipped to avoid feedback loops.
+    static IS_SHIPPER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A message to the shipper thread.
+enum Message {
+    Log(Value),
+    /// Submits all pending logs, then acknowledges through the sender.
+    Flush(mpsc::Sender<()>),
+}
+
+/// A layer that ships events as logs to Datadog's logs intake.
+///
+/// Logs are tagged for unified service tagging and correlated with the current trace, if any.
+#[derive(Clone, Debug)]
+pub(crate) struct LogLayer {
+    sender: SyncSender<Message>,
+    service: String,
+    /// The `ddtags` of every log, as comma-separated `key:value` pairs.
+    tags: String,
+}
+
+impl LogLayer {
+    /// Creates a layer for a config, starting the shipper thread.
+    pub(crate) fn new(config: &crate::Config, intake: Intake) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("dd-log-shipper".into())
+            .spawn(move || {
+                IS_SHIPPER.set(true);
+                ship(&intake, &receiver);
+            })
+            .expect("failed to start Datadog log shipper");
+
+        let tags = [("env", &config.env), ("version", &config.version)]
+            .into_iter()
+            .chain(config.tags.iter().map(|(key, value)| (key.as_str(), value)))
+            .map(|(key, value)| format!("{key}:{value}"))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Self {
+            sender,
+            service: config.service.clone(),
+            tags,
+        }
+    }
+
+    /// Submits all pending logs, waiting until done or timed out.
+    pub(crate) fn flush(&self) {
+        let (ack, done) = mpsc::channel();
+        if self.sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if IS_SHIPPER.get() {
+            return;
+        }
+
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+        let mut log = visitor.0;
+
+        let metadata = event.metadata();
+        let message = log.remove("message").unwrap_or_default();
+        log.insert("message".into(), message);
+        log.insert(
+            "status".into(),
+            metadata.level().as_str().to_ascii_lowercase().into(),
+        );
+        log.insert("service".into(), self.service.clone().into());
+        log.insert("ddsource".into(), "rust".into());
+        log.insert("ddtags".into(), self.tags.clone().into());
+        log.insert("logger".into(), json!({ "name": metadata.target() }));
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        log.insert("timestamp".into(), timestamp.into());
+
+        let context = tracing::Span::current().get_context();
+        if context.trace_id != 0 {
+            // Datadog correlates logs by the lower 64 bits of the trace ID.
+            log.insert(
+                "dd.trace_id".into(),
+                (context.trace_id as u64).to_string().into(),
+            );
+            log.insert("dd.span_id".into(), context.parent_id.to_string().into());
+        }
+
+        // Drop logs rather than block the caller if the intake can't keep up.
+        let _ = self.sender.try_send(Message::Log(Value::Object(log)));
+    }
+}
+
+/// Collects event fields into a JSON object.
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// Submits logs in batches until the layer is dropped.
+fn ship(intake: &Intake, receiver: &mpsc::Receiver<Message>) {
+    let client = Intake::client();
+    let mut batch = Vec::new();
+    let mut next_flush = Instant::now() + FLUSH_INTERVAL;
+
+    loop {
+        let timeout = next_flush.saturating_duration_since(Instant::now());
+        let ack = match receiver.recv_timeout(timeout) {
+            Ok(Message::Log(log)) => {
+                batch.push(log);
+                if batch.len() < MAX_BATCH_SIZE {
+                    continue;
+                }
+                None
+            }
+            Ok(Message::Flush(ack)) => Some(ack),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                submit(intake, &client, &mut batch);
+                return;
+            }
+        };
+
+        submit(intake, &client, &mut batch);
+        next_flush = Instant::now() + FLUSH_INTERVAL;
+        if let Some(ack) = ack {
+            let _ = ack.send(());
+        }
+    }
+}
+
+/// Submits a batch of logs, if any.
+fn submit(intake: &Intake, client: &reqwest::blocking::Client, batch: &mut Vec<Value>) {
+    if !batch.is_empty() {
+        intake.post(
+            client,
+            &intake.logs_url,
+            &Value::Array(std::mem::take(batch)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agentless::tests::stand_in_intake;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn logs_are_shipped_with_service_tags() {
+        let (intake, requests) = stand_in_intake();
+        let config = crate::Config::builder()
+            .service("payments")
+            .env("production")
+            .version("1.2.3")
+            .tag("team", "payments")
+            .build()
+            .unwrap();
+        let layer = LogLayer::new(&config, intake);
+
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(payment_id = 42, "capture failed");
+        });
+        layer.flush();
+
+        let request = requests.recv_timeout(FLUSH_TIMEOUT).unwrap();
+        assert_eq!(request.path, "/api/v2/logs");
+        assert_eq!(request.header("dd-api-key"), Some("test-api-key"));
+
+        let log = &request.body[0];
+        assert_eq!(log["message"], "capture failed");
+        assert_eq!(log["status"], "warn");
+        assert_eq!(log["service"], "payments");
+        assert_eq!(log["ddtags"], "env:production,version:1.2.3,team:payments");
+        assert_eq!(log["payment_id"], 42);
+    }
+}
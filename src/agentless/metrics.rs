@@ -0,0 +1,358 @@
+//! Metric aggregation and submission to Datadog's metrics intake.
+//!
+//! The DogStatsD client sends its datagrams to a local socket, where they are aggregated like the
+//! agent would, and submitted through the HTTP series API.
+
+use super::Intake;
+use serde_json::{Value, json};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How often aggregated metrics are submitted, matching the agent's flush interval.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long [`MetricsAggregator::flush`] waits for pending metrics to be submitted.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A datagram that makes the aggregator submit all pending metrics.
+const FLUSH_DATAGRAM: &[u8] = b"_komoju_datadog.flush";
+
+/// Aggregates DogStatsD datagrams and submits them to Datadog's metrics intake.
+#[derive(Debug)]
+pub(crate) struct MetricsAggregator {
+    addr: SocketAddr,
+    /// The number of completed flushes, to wait for in [`MetricsAggregator::flush`].
+    flushes: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl MetricsAggregator {
+    /// Starts an aggregator on a local socket, to send DogStatsD datagrams to.
+    pub(crate) fn start(intake: Intake) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("127.0.0.1", 0))?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let addr = socket.local_addr()?;
+        let flushes = Arc::new((Mutex::new(0), Condvar::new()));
+
+        let thread_flushes = flushes.clone();
+        thread::Builder::new()
+            .name("dd-metrics-aggregator".into())
+            .spawn(move || aggregate(&socket, &intake, &thread_flushes))?;
+
+        Ok(Self { addr, flushes })
+    }
+
+    /// Returns the address to send DogStatsD datagrams to.
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Submits all pending metrics, waiting until done or timed out.
+    ///
+    /// Datagrams sent before this call are included.
+    pub(crate) fn flush(&self) {
+        let (flushes, flushed) = &*self.flushes;
+        let before = *flushes.lock().unwrap();
+
+        let Ok(socket) = UdpSocket::bind(("127.0.0.1", 0)) else {
+            return;
+        };
+        if socket.send_to(FLUSH_DATAGRAM, self.addr).is_err() {
+            return;
+        }
+
+        let _ = flushed.wait_timeout_while(flushes.lock().unwrap(), FLUSH_TIMEOUT, |flushes| {
+            *flushes == before
+        });
+    }
+}
+
+/// Receives and aggregates datagrams, submitting them every [`FLUSH_INTERVAL`].
+fn aggregate(socket: &UdpSocket, intake: &Intake, flushes: &(Mutex<u64>, Condvar)) {
+    let client = Intake::client();
+    let mut metrics = Metrics::default();
+    let mut next_flush = Instant::now() + FLUSH_INTERVAL;
+    let mut buf = vec![0; u16::MAX as usize];
+
+    loop {
+        let flush_requested = match socket.recv(&mut buf) {
+            Ok(len) if &buf[..len] == FLUSH_DATAGRAM => true,
+            Ok(len) => {
+                for line in String::from_utf8_lossy(&buf[..len]).lines() {
+                    metrics.add(line);
+                }
+                false
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
+            Err(_) => return,
+        };
+
+        if flush_requested || Instant::now() >= next_flush {
+            metrics.submit(intake, &client);
+            next_flush = Instant::now() + FLUSH_INTERVAL;
+
+            let (flushes, flushed) = flushes;
+            *flushes.lock().unwrap() += 1;
+            flushed.notify_all();
+        }
+    }
+}
+
+/// The aggregated value of a metric context.
+#[derive(Debug, PartialEq)]
+enum Aggregate {
+    Count(f64),
+    Gauge(f64),
+    Set(HashSet<String>),
+    Histogram(Vec<f64>),
+    Distribution(Vec<f64>),
+}
+
+/// Metrics aggregated since the last flush, by name and sorted tags.
+#[derive(Debug, Default)]
+struct Metrics {
+    contexts: HashMap<(String, Vec<String>), Aggregate>,
+}
+
+impl Metrics {
+    /// Adds a DogStatsD metric line, ignoring events, service checks and invalid lines.
+    fn add(&mut self, line: &str) {
+        let mut parts = line.split('|');
+        let Some((name, value)) = parts.next().and_then(|metric| metric.split_once(':')) else {
+            return;
+        };
+        let Some(kind) = parts.next() else {
+            return;
+        };
+
+        let mut sample_rate = 1.0;
+        let mut tags = Vec::new();
+        for part in parts {
+            if let Some(rate) = part.strip_prefix('@') {
+                sample_rate = rate.parse().unwrap_or(1.0);
+            } else if let Some(part_tags) = part.strip_prefix('#') {
+                tags.extend(part_tags.split(',').map(str::to_string));
+            }
+        }
+        tags.sort();
+
+        let key = (name.to_string(), tags);
+        if kind == "s" {
+            match self.contexts.get_mut(&key) {
+                Some(Aggregate::Set(values)) => {
+                    values.insert(value.to_string());
+                }
+                _ => {
+                    self.contexts
+                        .insert(key, Aggregate::Set(HashSet::from([value.to_string()])));
+                }
+            }
+            return;
+        }
+
+        let Ok(value) = value.parse::<f64>() else {
+            return;
+        };
+        let aggregate = self.contexts.get_mut(&key);
+        match (kind, aggregate) {
+            ("c", Some(Aggregate::Count(count))) => *count += value / sample_rate,
+            ("g", Some(Aggregate::Gauge(gauge))) => *gauge = value,
+            ("h" | "ms", Some(Aggregate::Histogram(values)))
+            | ("d", Some(Aggregate::Distribution(values))) => values.push(value),
+            ("c", _) => {
+                self.contexts
+                    .insert(key, Aggregate::Count(value / sample_rate));
+            }
+            ("g", _) => {
+                self.contexts.insert(key, Aggregate::Gauge(value));
+            }
+            ("h" | "ms", _) => {
+                self.contexts.insert(key, Aggregate::Histogram(vec![value]));
+            }
+            ("d", _) => {
+                self.contexts
+                    .insert(key, Aggregate::Distribution(vec![value]));
+            }
+            _ => {}
+        }
+    }
+
+    /// Converts the aggregated metrics into series and distribution points payloads, resetting
+    /// them.
+    ///
+    /// Histograms are reported as `.avg`, `.count`, `.max`, `.median` and `.95percentile`, like
+    /// the agent does by default.
+    fn take_payloads(&mut self, timestamp: u64) -> (Vec<Value>, Vec<Value>) {
+        let interval = FLUSH_INTERVAL.as_secs();
+        let point = |value: f64| json!([{ "timestamp": timestamp, "value": value }]);
+        // Series types of the v2 API.
+        let (count, gauge) = (1, 3);
+
+        let mut series = Vec::new();
+        let mut distributions = Vec::new();
+        for ((name, tags), aggregate) in self.contexts.drain() {
+            let mut push = |name: &str, kind: u8, value: f64| {
+                series.push(json!({
+                    "metric": name,
+                    "type": kind,
+                    "interval": interval,
+                    "points": point(value),
+                    "tags": tags,
+                }));
+            };
+
+            match aggregate {
+                Aggregate::Count(value) => push(&name, count, value),
+                Aggregate::Gauge(value) => push(&name, gauge, value),
+                Aggregate::Set(values) => push(&name, gauge, values.len() as f64),
+                Aggregate::Histogram(mut values) => {
+                    values.sort_by(f64::total_cmp);
+                    let len = values.len();
+                    let quantile =
+                        |q: f64| values[((len as f64 * q).ceil() as usize).clamp(1, len) - 1];
+                    push(
+                        &format!("{name}.avg"),
+                        gauge,
+                        values.iter().sum::<f64>() / len as f64,
+                    );
+                    push(&format!("{name}.count"), count, len as f64);
+                    push(&format!("{name}.max"), gauge, values[len - 1]);
+                    push(&format!("{name}.median"), gauge, quantile(0.5));
+                    push(&format!("{name}.95percentile"), gauge, quantile(0.95));
+                }
+                Aggregate::Distribution(values) => distributions.push(json!({
+                    "metric": name,
+                    "points": [[timestamp, values]],
+                    "tags": tags,
+                    "type": "distribution",
+                })),
+            }
+        }
+        (series, distributions)
+    }
+
+    /// Submits the aggregated metrics, resetting them.
+    fn submit(&mut self, intake: &Intake, client: &reqwest::blocking::Client) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let (series, distributions) = self.take_payloads(timestamp);
+
+        if !series.is_empty() {
+            intake.post(client, &intake.series_url, &json!({ "series": series }));
+        }
+        if !distributions.is_empty() {
+            intake.post(
+                client,
+                &intake.distribution_points_url,
+                &json!({ "series": distributions }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agentless::tests::stand_in_intake;
+
+    #[test]
+    fn lines_are_aggregated() {
+        let mut metrics = Metrics::default();
+        metrics.add("payments.count:1|c|#team:a,env:test");
+        metrics.add("payments.count:1|c|@0.5|#env:test,team:a");
+        metrics.add("payments.amount:5|g");
+        metrics.add("payments.amount:7|g");
+        metrics.add("payments.merchants:a|s");
+        metrics.add("payments.merchants:b|s");
+        metrics.add("payments.merchants:a|s");
+        metrics.add("_e{5,4}:title|text");
+        metrics.add("_sc|payments|0");
+
+        let key = |name: &str, tags: &[&str]| {
+            (
+                name.to_string(),
+                tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(metrics.contexts.len(), 3);
+        assert_eq!(
+            metrics.contexts[&key("payments.count", &["env:test", "team:a"])],
+            Aggregate::Count(3.0)
+        );
+        assert_eq!(
+            metrics.contexts[&key("payments.amount", &[])],
+            Aggregate::Gauge(7.0)
+        );
+        assert!(matches!(
+            &metrics.contexts[&key("payments.merchants", &[])],
+            Aggregate::Set(values) if values.len() == 2
+        ));
+    }
+
+    #[test]
+    fn histograms_are_summarized() {
+        let mut metrics = Metrics::default();
+        for value in 1..=100 {
+            metrics.add(&format!("payments.latency:{value}|ms"));
+        }
+
+        let (series, distributions) = metrics.take_payloads(1_700_000_000);
+        let value = |name: &str| {
+            series
+                .iter()
+                .find(|series| series["metric"] == name)
+                .map(|series| series["points"][0]["value"].clone())
+        };
+        assert!(distributions.is_empty());
+        assert_eq!(value("payments.latency.avg"), Some(json!(50.5)));
+        assert_eq!(value("payments.latency.count"), Some(json!(100.0)));
+        assert_eq!(value("payments.latency.max"), Some(json!(100.0)));
+        assert_eq!(value("payments.latency.median"), Some(json!(50.0)));
+        assert_eq!(value("payments.latency.95percentile"), Some(json!(95.0)));
+        assert!(metrics.contexts.is_empty());
+    }
+
+    #[test]
+    fn metrics_are_submitted_on_flush() {
+        let (intake, requests) = stand_in_intake();
+        let aggregator = MetricsAggregator::start(intake).unwrap();
+
+        let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        for datagram in [
+            "payments.count:1|c|#env:test",
+            "payments.count:2|c|#env:test",
+            "payments.amount:1500|d|#env:test",
+        ] {
+            client
+                .send_to(datagram.as_bytes(), aggregator.addr())
+                .unwrap();
+        }
+        aggregator.flush();
+
+        let mut requests: Vec<_> = (0..2)
+            .map(|_| requests.recv_timeout(FLUSH_TIMEOUT).unwrap())
+            .collect();
+        requests.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(requests[0].path, "/api/v1/distribution_points");
+        assert_eq!(requests[0].body["series"][0]["metric"], "payments.amount");
+        assert_eq!(
+            requests[0].body["series"][0]["points"][0][1],
+            json!([1500.0])
+        );
+
+        assert_eq!(requests[1].path, "/api/v2/series");
+        assert_eq!(requests[1].header("dd-api-key"), Some("test-api-key"));
+        let series = &requests[1].body["series"][0];
+        assert_eq!(series["metric"], "payments.count");
+        assert_eq!(series["type"], 1);
+        assert_eq!(series["points"][0]["value"], 3.0);
+        assert_eq!(series["tags"], json!(["env:test"]));
+    }
+}
@@ -0,0 +1,331 @@
+//! Span submission to Datadog's OTLP traces intake.
+//!
+//! The trace exporter only speaks the agent's protocol, so it sends its payloads to a local relay
+//! instead, which converts the spans to OTLP and submits them.
+
+use super::Intake;
+use serde_json::{Value, json};
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+/// The headers that Datadog's OTLP traces intake requires, besides the API key.
+const OTLP_HEADERS: [(&str, &str); 2] = [("dd-protocol", "otlp"), ("dd-otlp-source", "datadog")];
+
+/// OTLP span kinds, by Datadog `span.kind`.
+const SPAN_KINDS: [(&str, u8); 5] = [
+    ("internal", 1),
+    ("server", 2),
+    ("client", 3),
+    ("producer", 4),
+    ("consumer", 5),
+];
+
+/// Relays the trace exporter's payloads to Datadog's OTLP traces intake.
+///
+/// Returns the local address to send traces to instead of the agent.
+pub(crate) fn relay_traces(config: &crate::Config, intake: Intake) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let addr = listener.local_addr()?;
+    let resource = json!({
+        "attributes": [
+            attribute("service.name", json!({ "stringValue": config.service })),
+            attribute("deployment.environment", json!({ "stringValue": config.env })),
+            attribute("service.version", json!({ "stringValue": config.version })),
+        ],
+    });
+
+    thread::Builder::new()
+        .name("dd-trace-relay".into())
+        .spawn(move || {
+            let client = Intake::client();
+            let (intake, resource) = (Arc::new(intake), Arc::new(resource));
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        tracing::warn!(%error, "failed to accept traces for Datadog intake");
+                        continue;
+                    }
+                };
+                // Each connection gets its own thread, as the exporter keeps them alive.
+                let (intake, client, resource) = (intake.clone(), client.clone(), resource.clone());
+                let spawned = thread::Builder::new()
+                    .name("dd-trace-relay-conn".into())
+                    .spawn(move || {
+                        if let Err(error) = relay_connection(stream, &intake, &client, &resource) {
+                            tracing::warn!(%error, "failed to relay traces to Datadog intake");
+                        }
+                    });
+                if let Err(error) = spawned {
+                    tracing::warn!(%error, "failed to relay traces to Datadog intake");
+                }
+            }
+        })?;
+
+    Ok(addr)
+}
+
+/// Answers the exporter's requests on a connection, submitting the spans of each.
+///
+/// Requests with a chunked body are rejected, as the exporter always sends a `content-length`.
+fn relay_connection(
+    stream: TcpStream,
+    intake: &Intake,
+    client: &reqwest::blocking::Client,
+    resource: &Value,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+
+        let (mut content_length, mut chunked) = (0, false);
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, "invalid content-length")
+                })?;
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = true;
+            }
+        }
+
+        if chunked {
+            reader.get_mut().write_all(
+                b"HTTP/1.1 411 Length Required\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            )?;
+            return Ok(());
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let traces = match rmp_serde::from_slice::<Vec<Vec<Value>>>(&body) {
+            Ok(traces) => traces,
+            Err(error) => {
+                tracing::warn!(%error, "failed to decode traces for Datadog intake");
+                reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")?;
+                continue;
+            }
+        };
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")?;
+
+        let spans = traces.iter().flatten().map(otlp_span).collect::<Vec<_>>();
+        if !spans.is_empty() {
+            let payload = json!({
+                "resourceSpans": [{
+                    "resource": resource,
+                    "scopeSpans": [{ "spans": spans }],
+                }],
+            });
+            intake.post_with_headers(client, &intake.traces_url, &OTLP_HEADERS, &payload);
+        }
+    }
+}
+
+/// Converts a span from the agent's v0.4 format to OTLP.
+///
+/// Datadog maps the `operation.name`, `resource.name` and `span.type` attributes back to the
+/// span's operation, resource and type.
+fn otlp_span(span: &Value) -> Value {
+    let id = |key: &str| span[key].as_u64().unwrap_or_default();
+    let meta = span["meta"].as_object();
+    let tag = |key: &str| meta.and_then(|meta| meta.get(key)?.as_str());
+
+    // The exporter sends the high bits of 128-bit trace IDs as a tag.
+    let trace_id_high = tag("_dd.p.tid")
+        .and_then(|high| u64::from_str_radix(high, 16).ok())
+        .unwrap_or_default();
+    let start = span["start"].as_i64().unwrap_or_default();
+    let end = start + span["duration"].as_i64().unwrap_or_default();
+    let kind = tag("span.kind")
+        .and_then(|kind| SPAN_KINDS.iter().find(|(name, _)| *name == kind))
+        .map_or(1, |(_, kind)| *kind);
+
+    let mut attributes = vec![
+        attribute("operation.name", json!({ "stringValue": span["name"] })),
+        attribute("resource.name", json!({ "stringValue": span["resource"] })),
+        attribute("span.type", json!({ "stringValue": span["type"] })),
+    ];
+    attributes.extend(
+        meta.into_iter()
+            .flatten()
+            .map(|(key, value)| attribute(key, json!({ "stringValue": value }))),
+    );
+    attributes.extend(
+        span["metrics"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| attribute(key, json!({ "doubleValue": value }))),
+    );
+
+    let mut otlp = json!({
+        "traceId": format!("{trace_id_high:016x}{:016x}", id("trace_id")),
+        "spanId": format!("{:016x}", id("span_id")),
+        "name": span["name"],
+        "kind": kind,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": attributes,
+        "status": { "code": 0 },
+    });
+    // Spans are marked as errors through error tags, see `crate::tracing::record_error`.
+    if span["error_code"].as_i64().unwrap_or_default() != 0 || tag("error.type").is_some() {
+        otlp["status"] = json!({ "code": 2, "message": tag("error.message").unwrap_or_default() });
+    }
+    if id("parent_id") != 0 {
+        otlp["parentSpanId"] = format!("{:016x}", id("parent_id")).into();
+    }
+    otlp
+}
+
+/// Returns an OTLP attribute.
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agentless::tests::stand_in_intake;
+    use std::time::Duration;
+    use tracing_datadog::DatadogTraceLayer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans_are_submitted_as_otlp() {
+        let (intake, requests) = stand_in_intake();
+        let config = crate::Config::builder()
+            .service("payments")
+            .env("production")
+            .version("1.2.3")
+            .build()
+            .unwrap();
+        let addr = relay_traces(&config, intake).unwrap();
+
+        let layer = DatadogTraceLayer::builder()
+            .service("payments")
+            .env("production")
+            .version("1.2.3")
+            .agent_address(addr.to_string())
+            .build()
+            .unwrap();
+        // Kept until the end, as dropping the layer stops the exporter.
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let parent = tracing::info_span!(
+                "capture",
+                operation = "payments.capture",
+                resource = "POST /payments",
+                span.kind = "server",
+            );
+            let _guard = parent.enter();
+            tracing::info_span!(
+                "authorize",
+                error.type = "CardDeclined",
+                error.message = "card declined",
+            )
+            .in_scope(|| {});
+        });
+
+        let request = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(request.path, "/api/v0.2/traces");
+        assert_eq!(request.header("dd-api-key"), Some("test-api-key"));
+        assert_eq!(request.header("dd-protocol"), Some("otlp"));
+        assert_eq!(request.header("dd-otlp-source"), Some("datadog"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+
+        let resource_spans = &request.body["resourceSpans"][0];
+        assert!(
+            resource_spans["resource"]["attributes"]
+                .as_array()
+                .unwrap()
+                .contains(&attribute(
+                    "service.name",
+                    json!({ "stringValue": "payments" })
+                ))
+        );
+
+        let spans = resource_spans["scopeSpans"][0]["spans"].as_array().unwrap();
+        let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap();
+        let (parent, child) = (span("payments.capture"), span("authorize"));
+        assert_eq!(parent["kind"], 2);
+        assert!(
+            parent["attributes"]
+                .as_array()
+                .unwrap()
+                .contains(&attribute(
+                    "resource.name",
+                    json!({ "stringValue": "POST /payments" })
+                ))
+        );
+        assert_eq!(parent["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(child["traceId"], parent["traceId"]);
+        assert_eq!(child["parentSpanId"], parent["spanId"]);
+        assert_eq!(parent.get("parentSpanId"), None);
+        assert_eq!(parent["status"]["code"], 0);
+        assert_eq!(child["status"]["code"], 2);
+        assert_eq!(child["status"]["message"], "card declined");
+    }
+
+    /// Sends a raw request to the relay, returning its status line.
+    fn send(stream: &mut TcpStream, request: &[u8]) -> String {
+        stream.write_all(request).unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        status.trim_end().to_string()
+    }
+
+    #[test]
+    fn relay_answers_after_decoding() {
+        let (intake, requests) = stand_in_intake();
+        let config = crate::Config::builder().build().unwrap();
+        let addr = relay_traces(&config, intake).unwrap();
+
+        // An idle connection does not keep others from being answered.
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        assert_eq!(
+            send(
+                &mut stream,
+                b"PUT /v0.4/traces HTTP/1.1\r\ncontent-length: 2\r\n\r\n{}"
+            ),
+            "HTTP/1.1 400 Bad Request"
+        );
+        // An empty array of traces.
+        assert_eq!(
+            send(
+                &mut stream,
+                b"PUT /v0.4/traces HTTP/1.1\r\ncontent-length: 1\r\n\r\n\x90"
+            ),
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            send(
+                &mut stream,
+                b"PUT /v0.4/traces HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n1\r\n\x90\r\n0\r\n\r\n"
+            ),
+            "HTTP/1.1 411 Length Required"
+        );
+        assert!(requests.try_recv().is_err());
+    }
+}
@@ -1,114 +1,35 @@
-//! `OpenTelemetry` http_server helper functions.
+//! Axum integration, built on the framework-agnostic [`HttpTraceLayer`].
 //!
 //! Adapted from <https://github.com/will-bank/datadog-tracing>.
 
-use axum::extract::{ConnectInfo, MatchedPath};
-use http::{Request, Response, header};
-use pin_project_lite::pin_project;
-use std::{
-    error::Error,
-    future::Future,
-    net::SocketAddr,
-    pin::Pin,
-    task::{Context, Poll},
+use crate::{
+    http::{Framework, HttpTraceLayer, RejectionInfo},
+    statsd::StatsD,
 };
-use tower::{Layer, Service};
-use tracing::{Span, field::Empty};
-use tracing_datadog::{
-    context::{TraceContextExt, TracingContextExt},
-    http::W3CTraceContextHeaders,
+use axum::{
+    extract::{
+        ConnectInfo, FromRequest, FromRequestParts, MatchedPath,
+        rejection::{FormRejection, JsonRejection, PathRejection, QueryRejection},
+    },
+    response::{IntoResponse, IntoResponseParts, ResponseParts},
 };
+use http::{StatusCode, request::Parts};
+use std::{convert::Infallible, fmt::Display, net::SocketAddr};
+use tower::Layer;
+use tracing::Span;
 
-/// Creates a span from a request.
-fn make_span_from_request<B>(req: &Request<B>) -> Span {
-    let http_method = req.method().as_str();
-    let client_ip = req
-        .headers()
-        .get("X-Forwarded-For")
-        .and_then(|h| h.to_str().ok().map(|s| s.to_string()))
-        .or_else(|| {
-            req.extensions()
-                .get::<ConnectInfo<SocketAddr>>()
-                .map(|addr| addr.ip().to_string())
-        });
-    let request_id = req
-        .headers()
-        .get("X-Request-Id")
-        .and_then(|h| h.to_str().ok().map(|s| s.to_string()));
-    tracing::info_span!(
-        "HTTP request",
-        operation = "axum.request",
-        resource = format!("{} {}", http_method, crate::http::path_group(req.uri().path())),
-        http.base_url = req.headers().get(header::HOST).and_then(|h| h.to_str().ok()).or(req.uri().host()),
-        http.method = %http_method,
-        http.url = req.uri().path(),
-        http.useragent = req.headers().get(header::USER_AGENT).and_then(|h| h.to_str().ok()),
-        http.route = Empty,
-        http.client.ip = client_ip,
-        http.request_id = request_id,
-        http.status_code = Empty,
-        network.protocol.version = match req.version() {
-            http::Version::HTTP_10 => "1.0",
-            http::Version::HTTP_11 => "1.1",
-            http::Version::HTTP_2 => "2.0",
-            http::Version::HTTP_3 => "3.0",
-            _ => "",
-        },
-        server.address = req.uri().host(),
-        url.scheme = req.uri().scheme_str(),
-        request_id = Empty,
-        error.type = Empty,
-        error.message = Empty,
-        span.kind = "server",
-        span.type = "web",
-
-        // Our internal authentication claims
-        auth.method = Empty,
-        auth.user_uuid = Empty,
-        auth.merchant_uuid = Empty,
-        auth.account_uuid = Empty,
-        auth.role = Empty,
-        auth.api_version = Empty,
-
-        // Datadog AppSec identity tags
-        usr.id = Empty,
-        usr.email = Empty,
-        usr.session_id = Empty,
-        usr.role = Empty,
-        usr.merchant = Empty,
-        usr.account = Empty,
-    )
-}
-
-/// Updates a span with tags from the response.
-fn update_span_from_response<B>(span: &Span, response: &Response<B>) {
-    span.record("http.status_code", response.status().as_u16());
-}
+pub use crate::http::{LoadRejection, RequestSpan, ResourceNamer, ResponseFuture, TracedBody};
 
-/// Updates a span with tags from an error response.
-fn update_span_from_error<E>(span: &Span, error: &E)
-where
-    E: Error,
-{
-    span.record("otel.status_code", "ERROR");
-    span.record("error.type", error.to_string());
-    error
-        .source()
-        .map(|s| span.record("error.message", s.to_string()));
-}
+#[cfg(feature = "axum_ws")]
+pub mod ws;
 
-/// Updates a span with tags from a response or error.
-fn update_span_from_response_or_error<B, E>(span: &Span, response: &Result<Response<B>, E>)
-where
-    E: Error,
-{
-    match response {
-        Ok(response) => update_span_from_response(span, response),
-        Err(err) => update_span_from_error(span, err),
-    }
-}
-
-/// Axum Layer to create OTel spans for requests.
+/// Axum Layer to create spans for requests.
+///
+/// This is an [`HttpTraceLayer`] that additionally records the matched route as `http.route`, uses
+/// it for the resource and metrics, and takes the client IP from [`ConnectInfo`].
+///
+/// The [`AxumTraceLayer`](const@AxumTraceLayer) constant is a layer with the default settings,
+/// equivalent to [`AxumTraceLayer::new()`].
 ///
 /// # Examples
 ///
@@ -120,7 +41,7 @@ where
 /// axum::Router::new()
 ///   // Example route that creates a span for each request.
 ///   .route("/sign_in", post(sign_in))
-///   .layer(AxumTraceLayer)
+///   .layer(AxumTraceLayer::new())
 ///   // No traces on health checks.
 ///   .route("/health_check", get(health_check));
 ///
@@ -128,98 +49,347 @@ where
 /// # async fn health_check() {}
 /// ```
 #[derive(Clone, Debug)]
-pub struct AxumTraceLayer;
+pub struct AxumTraceLayer {
+    inner: HttpTraceLayer,
+}
+
+/// An [`AxumTraceLayer`](struct@AxumTraceLayer) with the default settings.
+///
+/// This keeps `.layer(AxumTraceLayer)` working from when the layer had no settings.
+#[allow(non_upper_case_globals)]
+pub const AxumTraceLayer: AxumTraceLayer = AxumTraceLayer::new();
+
+impl Default for AxumTraceLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AxumTraceLayer {
+    /// Creates a new layer with the default settings.
+    pub const fn new() -> Self {
+        Self {
+            inner: HttpTraceLayer::new().framework(Framework {
+                operation: "axum.request",
+                route: |extensions| extensions.get::<MatchedPath>().map(MatchedPath::as_str),
+                peer_addr: |extensions| {
+                    extensions
+                        .get::<ConnectInfo<SocketAddr>>()
+                        .map(|ConnectInfo(addr)| *addr)
+                },
+            }),
+        }
+    }
+
+    /// See [`HttpTraceLayer::trace_response_body`].
+    pub fn trace_response_body(self, enabled: bool) -> Self {
+        Self {
+            inner: self.inner.trace_response_body(enabled),
+        }
+    }
+
+    /// See [`HttpTraceLayer::cancelled_status_code`].
+    pub fn cancelled_status_code(self, status_code: u16) -> Self {
+        Self {
+            inner: self.inner.cancelled_status_code(status_code),
+        }
+    }
+
+    /// See [`HttpTraceLayer::metrics`].
+    pub fn metrics(self, statsd: &'static StatsD) -> Self {
+        Self {
+            inner: self.inner.metrics(statsd),
+        }
+    }
+
+    /// See [`HttpTraceLayer::resource_namer`].
+    pub fn resource_namer(self, resource_namer: impl ResourceNamer) -> Self {
+        Self {
+            inner: self.inner.resource_namer(resource_namer),
+        }
+    }
+}
 
 impl<S> Layer<S> for AxumTraceLayer {
-    /// The wrapped service
     type Service = AxumTraceService<S>;
+
     fn layer(&self, inner: S) -> Self::Service {
-        AxumTraceService { inner }
+        self.inner.layer(inner)
     }
 }
 
-/// Middleware `Service` layer that creates OTel spans for every request.
-#[derive(Debug, Clone)]
-pub struct AxumTraceService<S> {
-    /// The inner service layer.
-    inner: S,
+/// Middleware `Service` that creates spans for every request, see [`AxumTraceLayer`](struct@AxumTraceLayer).
+pub type AxumTraceService<S> = crate::http::HttpTraceService<S>;
+
+impl IntoResponse for LoadRejection {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            Self::Timeout => StatusCode::REQUEST_TIMEOUT,
+            Self::Shed => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self, ()).into_response()
+    }
 }
 
-impl<S, B, B2> Service<Request<B>> for AxumTraceService<S>
-where
-    S: Service<Request<B>, Response = Response<B2>> + Clone + Send + 'static,
-    S::Error: Error + 'static,
-    S::Future: Send + 'static,
-    B: Send + 'static,
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = ResponseFuture<S::Future>;
+impl IntoResponseParts for LoadRejection {
+    type Error = Infallible;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(Into::into)
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+        Ok(res)
     }
+}
+
+/// Extractor rejections that can be traced by [`AxumTraceLayer`](struct@AxumTraceLayer).
+///
+/// Rejections converted through [`TracedRejection::into_traced_response`] tag the request span
+/// with `http.rejection.type` and `http.rejection.message`. If [metrics](AxumTraceLayer::metrics)
+/// are enabled, they are also counted in the `http.server.request.rejected` metric, tagged with
+/// the `rejection` type, `method` and `route`.
+///
+/// See [`Traced`] for an extractor that does this automatically.
+///
+/// # Examples
+///
+/// ```
+/// use axum::{
+///     Json,
+///     extract::rejection::JsonRejection,
+///     http::StatusCode,
+///     response::{IntoResponse, Response},
+/// };
+/// use komoju_datadog::axum::TracedRejection;
+///
+/// async fn create_payment(payload: Result<Json<u64>, JsonRejection>) -> Response {
+///     let Json(amount) = match payload {
+///         Ok(payload) => payload,
+///         Err(rejection) => return rejection.into_traced_response(),
+///     };
+///     (StatusCode::CREATED, Json(amount)).into_response()
+/// }
+/// ```
+pub trait TracedRejection: IntoResponse + Display + Sized {
+    /// Returns the type of rejection, e.g. `"JsonSyntaxError"`.
+    fn rejection_type(&self) -> &'static str;
 
-    fn call(&mut self, req: Request<B>) -> Self::Future {
-        let req = req;
-        let span = {
-            let span = make_span_from_request(&req);
+    /// Converts the rejection into a response, marking it for [`AxumTraceLayer`](struct@AxumTraceLayer).
+    fn into_traced_response(self) -> axum::response::Response {
+        let rejection = RejectionInfo {
+            rejection_type: self.rejection_type(),
+            message: self.to_string(),
+        };
+        let mut response = self.into_response();
+        response.extensions_mut().insert(rejection);
+        response
+    }
+}
 
-            let method = req.method().as_str();
-            let route = http_route(&req);
+impl TracedRejection for JsonRejection {
+    fn rejection_type(&self) -> &'static str {
+        match self {
+            Self::JsonDataError(_) => "JsonDataError",
+            Self::JsonSyntaxError(_) => "JsonSyntaxError",
+            Self::MissingJsonContentType(_) => "MissingJsonContentType",
+            Self::BytesRejection(_) => "BytesRejection",
+            _ => "JsonRejection",
+        }
+    }
+}
 
-            if !route.is_empty() {
-                span.record("resource", format!("{method} {route}").trim());
-                span.record("http.route", route);
-            }
+impl TracedRejection for PathRejection {
+    fn rejection_type(&self) -> &'static str {
+        match self {
+            Self::FailedToDeserializePathParams(_) => "FailedToDeserializePathParams",
+            Self::MissingPathParams(_) => "MissingPathParams",
+            _ => "PathRejection",
+        }
+    }
+}
 
-            span.set_context(
-                req.headers()
-                    .extract_trace_context::<W3CTraceContextHeaders>(),
-            );
+impl TracedRejection for QueryRejection {
+    fn rejection_type(&self) -> &'static str {
+        match self {
+            Self::FailedToDeserializeQueryString(_) => "FailedToDeserializeQueryString",
+            _ => "QueryRejection",
+        }
+    }
+}
 
-            span
-        };
-        let future = {
-            let _ = span.enter();
-            self.inner.call(req)
-        };
-        ResponseFuture {
-            inner: future,
-            span,
+impl TracedRejection for FormRejection {
+    fn rejection_type(&self) -> &'static str {
+        match self {
+            Self::InvalidFormContentType(_) => "InvalidFormContentType",
+            Self::FailedToDeserializeForm(_) => "FailedToDeserializeForm",
+            Self::FailedToDeserializeFormBody(_) => "FailedToDeserializeFormBody",
+            Self::BytesRejection(_) => "BytesRejection",
+            _ => "FormRejection",
         }
     }
 }
 
-pin_project! {
-    pub struct ResponseFuture<F> {
-        #[pin]
-        inner: F,
-        span: Span,
+/// Extractor wrapper that traces rejections of the inner extractor.
+///
+/// See [`TracedRejection`] for details.
+///
+/// # Examples
+///
+/// ```
+/// use axum::{Json, extract::Path};
+/// use komoju_datadog::axum::Traced;
+///
+/// async fn update_merchant(
+///     Traced(Path(merchant_id)): Traced<Path<String>>,
+///     Traced(Json(name)): Traced<Json<String>>,
+/// ) {
+///     // ...
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Traced<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for Traced<T>
+where
+    S: Send + Sync,
+    T: FromRequestParts<S>,
+    T::Rejection: TracedRejection,
+{
+    type Rejection = axum::response::Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        T::from_request_parts(parts, state)
+            .await
+            .map(Self)
+            .map_err(TracedRejection::into_traced_response)
     }
 }
 
-impl<Fut, ResBody, E> Future for ResponseFuture<Fut>
+impl<S, T> FromRequest<S> for Traced<T>
 where
-    Fut: Future<Output = Result<Response<ResBody>, E>>,
-    E: Error + 'static,
+    S: Send + Sync,
+    T: FromRequest<S>,
+    T::Rejection: TracedRejection,
 {
-    type Output = Result<Response<ResBody>, E>;
+    type Rejection = axum::response::Response;
+
+    async fn from_request(req: axum::extract::Request, state: &S) -> Result<Self, Self::Rejection> {
+        T::from_request(req, state)
+            .await
+            .map(Self)
+            .map_err(TracedRejection::into_traced_response)
+    }
+}
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        let _guard = this.span.enter();
-        let result = futures_util::ready!(this.inner.poll(cx));
-        update_span_from_response_or_error(this.span, &result);
+impl<S> FromRequestParts<S> for RequestSpan
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
 
-        Poll::Ready(result)
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .unwrap_or_else(|| Self {
+                span: Span::none(),
+                route: crate::http::path_group(parts.uri.path()),
+            }))
     }
 }
 
-/// Returns the route that matched a request, or an empty string.
-#[inline]
-fn http_route<B>(req: &Request<B>) -> &str {
-    req.extensions()
-        .get::<MatchedPath>()
-        .map_or_else(|| "", |mp| mp.as_str())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::with_recorder;
+    use axum::body::Body as AxumBody;
+    use http::{Request, header};
+    use tower::ServiceExt;
+
+    #[test]
+    fn handlers_can_set_resource() {
+        let (recorder, _) = with_recorder(async {
+            let router = axum::Router::new()
+                .route(
+                    "/graphql",
+                    axum::routing::post(|span: RequestSpan| async move {
+                        span.set_resource("POST /graphql getMerchant");
+                    }),
+                )
+                .layer(AxumTraceLayer::new());
+            let _ = router
+                .oneshot(Request::post("/graphql").body(AxumBody::empty()).unwrap())
+                .await;
+        });
+
+        assert_eq!(
+            recorder.get("resource").as_deref(),
+            Some("POST /graphql getMerchant")
+        );
+    }
+
+    #[test]
+    fn load_rejections_are_tagged() {
+        let (recorder, _) = with_recorder(async {
+            let service =
+                AxumTraceLayer::new().layer(tower::service_fn(|_req: Request<AxumBody>| async {
+                    let response = (http::StatusCode::SERVICE_UNAVAILABLE, LoadRejection::Shed);
+                    Ok::<_, Infallible>(response.into_response())
+                }));
+            let _ = service.oneshot(Request::new(AxumBody::empty())).await;
+        });
+
+        assert_eq!(recorder.get("http.shed").as_deref(), Some("true"));
+        assert_eq!(recorder.get("http.timeout"), None);
+    }
+
+    #[test]
+    fn extractor_rejections_are_tagged() {
+        let (recorder, _) = with_recorder(async {
+            let router = axum::Router::new()
+                .route(
+                    "/payments",
+                    axum::routing::post(|_: Traced<axum::Json<u64>>| async {}),
+                )
+                .layer(AxumTraceLayer::new());
+            let request = Request::post("/payments")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(AxumBody::from("nope"))
+                .unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        });
+
+        assert_eq!(
+            recorder.get("http.rejection.type").as_deref(),
+            Some("JsonSyntaxError")
+        );
+        assert!(recorder.get("http.rejection.message").is_some());
+    }
+
+    #[test]
+    fn matched_route_is_recorded() {
+        let (recorder, _) = with_recorder(async {
+            let router = axum::Router::new()
+                .route("/merchants/{id}", axum::routing::get(|| async {}))
+                // The constant spelling, from when the layer had no settings.
+                .layer(AxumTraceLayer);
+            let _ = router
+                .oneshot(
+                    Request::get("/merchants/abc123")
+                        .body(AxumBody::empty())
+                        .unwrap(),
+                )
+                .await;
+        });
+
+        assert_eq!(recorder.get("operation").as_deref(), Some("axum.request"));
+        assert_eq!(
+            recorder.get("resource").as_deref(),
+            Some("GET /merchants/{id}")
+        );
+        assert_eq!(
+            recorder.get("http.route").as_deref(),
+            Some("/merchants/{id}")
+        );
+    }
 }
@@ -0,0 +1,344 @@
+//! WebSocket session tracing.
+
+use super::RequestSpan;
+use crate::http::touch_span;
+use axum::{
+    Error,
+    extract::ws::{CloseFrame, Message, WebSocket},
+};
+use tracing::{Instrument, Span, field::Empty};
+
+/// A [`WebSocket`] that traces its session.
+///
+/// The session gets its own `websocket.session` span, linked to the span of the upgrade request.
+/// It stays open until the socket is dropped, and records the close code along with the number of
+/// messages and bytes received and sent.
+///
+/// Optionally, a `websocket.receive` span can be created per inbound message, see
+/// [`TracedWebSocket::message_spans`].
+///
+/// # Examples
+///
+/// ```
+/// use axum::{
+///     extract::ws::{Message, WebSocketUpgrade},
+///     response::Response,
+/// };
+/// use komoju_datadog::axum::{RequestSpan, ws::TracedWebSocket};
+/// use tracing::{Instrument, Span};
+///
+/// async fn notifications(ws: WebSocketUpgrade, span: RequestSpan) -> Response {
+///     ws.on_upgrade(move |socket| async move {
+///         let mut socket = TracedWebSocket::new(socket, &span).message_spans(true);
+///         while let Some(Ok(message)) = socket.recv().await {
+///             let message_span = socket.message_span().cloned().unwrap_or_else(Span::none);
+///             let reply = handle(message).instrument(message_span).await;
+///             if socket.send(reply).await.is_err() {
+///                 break;
+///             }
+///         }
+///     })
+/// }
+///
+/// async fn handle(message: Message) -> Message {
+///     message
+/// }
+/// ```
+#[derive(Debug)]
+pub struct TracedWebSocket {
+    inner: WebSocket,
+    span: Span,
+    /// Whether to create a span per inbound message.
+    message_spans: bool,
+    /// The span of the last inbound message, if any.
+    message_span: Option<Span>,
+    messages_received: u64,
+    bytes_received: u64,
+    messages_sent: u64,
+    bytes_sent: u64,
+    close_code: Option<u16>,
+}
+
+impl TracedWebSocket {
+    /// Wraps an upgraded socket, linking its session span to the upgrade request's span.
+    pub fn new(socket: WebSocket, request_span: &RequestSpan) -> Self {
+        let span = tracing::info_span!(
+            parent: None,
+            "WebSocket session",
+            operation = "websocket.session",
+            resource = request_span.route(),
+            websocket.close_code = Empty,
+            websocket.messages_received = Empty,
+            websocket.bytes_received = Empty,
+            websocket.messages_sent = Empty,
+            websocket.bytes_sent = Empty,
+            span.kind = "server",
+            span.type = "websocket",
+        );
+        span.follows_from(request_span.span());
+
+        Self {
+            inner: socket,
+            span,
+            message_spans: false,
+            message_span: None,
+            messages_received: 0,
+            bytes_received: 0,
+            messages_sent: 0,
+            bytes_sent: 0,
+            close_code: None,
+        }
+    }
+
+    /// Creates a `websocket.receive` span for every inbound text or binary message, which lasts
+    /// until the next call to [`recv`](Self::recv). The span is available through
+    /// [`message_span`](Self::message_span) to instrument the message handling.
+    ///
+    /// Disabled by default.
+    pub fn message_spans(mut self, enabled: bool) -> Self {
+        self.message_spans = enabled;
+        self
+    }
+
+    /// Receives the next message, see [`WebSocket::recv`].
+    pub async fn recv(&mut self) -> Option<Result<Message, Error>> {
+        self.finish_message_span();
+
+        let message = self.inner.recv().instrument(self.span.clone()).await;
+
+        match &message {
+            Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) => {
+                let length = message_length(message);
+                self.messages_received += 1;
+                self.bytes_received += length;
+                if self.message_spans {
+                    self.message_span = Some(tracing::info_span!(
+                        parent: &self.span,
+                        "WebSocket message",
+                        operation = "websocket.receive",
+                        websocket.message.type = message_type(message),
+                        websocket.message.length = length,
+                    ));
+                }
+            }
+            Some(Ok(Message::Close(frame))) => {
+                self.close_code = self.close_code.or(close_code(frame));
+            }
+            _ => {}
+        }
+
+        message
+    }
+
+    /// Sends a message, see [`WebSocket::send`].
+    pub async fn send(&mut self, message: Message) -> Result<(), Error> {
+        match &message {
+            Message::Text(_) | Message::Binary(_) => {
+                self.messages_sent += 1;
+                self.bytes_sent += message_length(&message);
+            }
+            Message::Close(frame) => {
+                self.close_code = self.close_code.or(close_code(frame));
+            }
+            _ => {}
+        }
+
+        self.inner.send(message).instrument(self.span.clone()).await
+    }
+
+    /// Returns the session span.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Returns the span of the last inbound message, if [message spans](Self::message_spans) are
+    /// enabled.
+    pub fn message_span(&self) -> Option<&Span> {
+        self.message_span.as_ref()
+    }
+
+    /// Ends the current message span, if any.
+    fn finish_message_span(&mut self) {
+        if let Some(span) = self.message_span.take() {
+            touch_span(&span);
+        }
+    }
+
+    /// Records the session tags.
+    fn finish(&mut self) {
+        self.finish_message_span();
+
+        let _guard = self.span.enter();
+        if let Some(close_code) = self.close_code {
+            self.span.record("websocket.close_code", close_code);
+        }
+        self.span
+            .record("websocket.messages_received", self.messages_received);
+        self.span
+            .record("websocket.bytes_received", self.bytes_received);
+        self.span
+            .record("websocket.messages_sent", self.messages_sent);
+        self.span.record("websocket.bytes_sent", self.bytes_sent);
+    }
+}
+
+impl Drop for TracedWebSocket {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Returns the payload length of a message.
+fn message_length(message: &Message) -> u64 {
+    let length = match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(bytes) | Message::Ping(bytes) | Message::Pong(bytes) => bytes.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |frame| frame.reason.len()),
+    };
+    length as u64
+}
+
+/// Returns the type of a message, for tagging.
+fn message_type(message: &Message) -> &'static str {
+    match message {
+        Message::Text(_) => "text",
+        Message::Binary(_) => "binary",
+        Message::Ping(_) => "ping",
+        Message::Pong(_) => "pong",
+        Message::Close(_) => "close",
+    }
+}
+
+/// Returns the close code of a close frame, if any.
+fn close_code(frame: &Option<CloseFrame>) -> Option<u16> {
+    frame.as_ref().map(|frame| frame.code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        axum::AxumTraceLayer,
+        test_util::{FieldRecorder, with_recorder},
+    };
+    use axum::{extract::ws::WebSocketUpgrade, routing::get};
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::oneshot;
+    use tokio_tungstenite::tungstenite::{
+        self,
+        protocol::{CloseFrame as ClientCloseFrame, frame::coding::CloseCode},
+    };
+
+    /// Runs an echo session where the client sends a text and a binary message, then closes the
+    /// socket with code 4000.
+    fn echo_session(message_spans: bool) -> FieldRecorder {
+        let (recorder, _) = with_recorder(async move {
+            let (done, session_ended) = oneshot::channel();
+            let done = Arc::new(Mutex::new(Some(done)));
+            let router = axum::Router::new()
+                .route(
+                    "/ws",
+                    get(move |ws: WebSocketUpgrade, span: RequestSpan| async move {
+                        ws.on_upgrade(move |socket| async move {
+                            let mut socket =
+                                TracedWebSocket::new(socket, &span).message_spans(message_spans);
+                            while let Some(Ok(message)) = socket.recv().await {
+                                let echo = matches!(message, Message::Text(_) | Message::Binary(_));
+                                if echo && socket.send(message).await.is_err() {
+                                    break;
+                                }
+                            }
+                            drop(socket);
+                            if let Some(done) = done.lock().unwrap().take() {
+                                let _ = done.send(());
+                            }
+                        })
+                    }),
+                )
+                .layer(AxumTraceLayer::new());
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, router).await });
+
+            let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+                .await
+                .unwrap();
+            client
+                .send(tungstenite::Message::text("hello"))
+                .await
+                .unwrap();
+            client
+                .send(tungstenite::Message::binary(vec![1, 2, 3]))
+                .await
+                .unwrap();
+            let _ = client.next().await;
+            let _ = client.next().await;
+            client
+                .close(Some(ClientCloseFrame {
+                    code: CloseCode::Library(4000),
+                    reason: "".into(),
+                }))
+                .await
+                .unwrap();
+            while client.next().await.is_some() {}
+
+            session_ended.await.unwrap();
+        });
+        recorder
+    }
+
+    #[test]
+    fn session_span_records_close_code_and_counters() {
+        let recorder = echo_session(false);
+
+        assert_eq!(
+            recorder.get("operation").as_deref(),
+            Some("websocket.session")
+        );
+        assert_eq!(recorder.get("resource").as_deref(), Some("/ws"));
+        assert_eq!(
+            recorder.get("websocket.close_code").as_deref(),
+            Some("4000")
+        );
+        assert_eq!(
+            recorder.get("websocket.messages_received").as_deref(),
+            Some("2")
+        );
+        assert_eq!(
+            recorder.get("websocket.bytes_received").as_deref(),
+            Some("8")
+        );
+        assert_eq!(
+            recorder.get("websocket.messages_sent").as_deref(),
+            Some("2")
+        );
+        assert_eq!(recorder.get("websocket.bytes_sent").as_deref(), Some("8"));
+        assert_eq!(recorder.get("websocket.message.type"), None);
+    }
+
+    #[test]
+    fn session_span_follows_from_upgrade_request() {
+        let recorder = echo_session(false);
+
+        assert_eq!(
+            recorder.get("follows_from").as_deref(),
+            Some("HTTP request")
+        );
+    }
+
+    #[test]
+    fn message_spans_are_created_per_message() {
+        let recorder = echo_session(true);
+
+        assert_eq!(
+            recorder.get("websocket.message.type").as_deref(),
+            Some("binary")
+        );
+        assert_eq!(
+            recorder.get("websocket.message.length").as_deref(),
+            Some("3")
+        );
+    }
+}
@@ -1,8 +1,11 @@
 //! Configuration
 
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::Duration;
 
 /// Observability configuration.
 ///
@@ -38,6 +41,13 @@ pub struct Config {
     /// Defaults to `development`.
     pub env: String,
 
+    /// The kind of environment the service runs in, which the defaults of other fields are derived
+    /// from.
+    ///
+    /// Defaults to the profile that [`env`](Config::env) maps to, see
+    /// [`ConfigBuilder::profile_for_env`].
+    pub profile: Profile,
+
     /// The `version` tag to use for metrics and traces.
     ///
     /// Can also be set via the `DD_VERSION` environment variable.
@@ -45,19 +55,144 @@ pub struct Config {
     /// Defaults to `unknown`.
     pub version: String,
 
-    /// The Datadog agent URL to send traces to.
+    /// The Datadog agent URL to send traces to, either `host:port` or `unix:///path/to/apm.socket`.
     ///
-    /// Can also be set via the `DD_TRACE_AGENT_URL` environment variable.
+    /// Can also be set via the `DD_TRACE_AGENT_URL` environment variable, or the other standard
+    /// Datadog variables listed in [`ConfigBuilder`].
     ///
-    /// Defaults to `None`.
+    /// Defaults to `None`, which disables tracing outside of [agentless mode](Config::api_key).
     pub trace_agent_url: Option<String>,
 
-    /// The Datadog agent URL to send statsD metrics to.
+    /// Whether traces are sent, to the trace agent or to Datadog's intake in
+    /// [agentless mode](Config::api_key).
+    ///
+    /// Can also be set via the `DD_TRACE_ENABLED` environment variable.
+    ///
+    /// Defaults to whether a `trace_agent_url` or an `api_key` is set.
+    pub trace_enabled: bool,
+
+    /// The fraction of traces to send, from `0.0` to `1.0`. Whether to send a trace is decided
+    /// when its root span starts in this service, and applies to all of its spans.
+    ///
+    /// Traces continued from another service are sampled the same way, independently of the
+    /// decision made upstream, so a rate below `1.0` is best kept to services where traces start.
+    /// Unsampled traces are not sent at all, so they are also missing from trace metrics.
+    ///
+    /// Can also be set via the `DD_TRACE_SAMPLE_RATE` environment variable.
+    ///
+    /// Defaults to `1.0`.
+    pub trace_sample_rate: f64,
+
+    /// The Datadog agent URL to send statsD metrics to, either `host:port` or a Unix socket URL
+    /// like `unix:///path/to/dsd.socket`.
     ///
-    /// Can also be set via the `DD_METRICS_AGENT_URL` environment variable.
+    /// Unix sockets use datagrams by default, like the agent. Use `unixstream://` for stream
+    /// sockets, or `unixgram://` to be explicit.
+    ///
+    /// Can also be set via the `DD_METRICS_AGENT_URL` environment variable, or the other standard
+    /// Datadog variables listed in [`ConfigBuilder`].
     ///
     /// Defaults to `localhost:8125`.
     pub metrics_agent_url: String,
+
+    /// Tags to add to all metrics and traces, as `(key, value)` pairs.
+    ///
+    /// Can also be set via the `DD_TAGS` environment variable, as comma- or space-separated
+    /// `key:value` pairs.
+    ///
+    /// Defaults to no tags.
+    pub tags: Vec<(String, String)>,
+
+    /// The URL of the service's git repository, for Datadog's source code integration.
+    ///
+    /// Added to traces as `git.repository_url`. Can also be set via the `DD_GIT_REPOSITORY_URL`
+    /// environment variable, or from [`BuildInfo`].
+    ///
+    /// Defaults to `None`.
+    pub git_repository_url: Option<String>,
+
+    /// The git commit SHA the service was built from, for Datadog's source code integration.
+    ///
+    /// Added to traces as `git.commit.sha`. Can also be set via the `DD_GIT_COMMIT_SHA`
+    /// environment variable, or from [`BuildInfo`].
+    ///
+    /// Defaults to `None`.
+    pub git_commit_sha: Option<String>,
+
+    /// The filter for logs and spans, in [`EnvFilter`](tracing_subscriber::EnvFilter) syntax.
+    ///
+    /// Can also be set via the `RUST_LOG` environment variable.
+    ///
+    /// Defaults to `info`.
+    pub log_filter: String,
+
+    /// The Datadog API key for agentless mode, which sends logs, metrics and traces directly to
+    /// Datadog instead of the agent. Requires the `agentless` feature.
+    ///
+    /// Traces are submitted to Datadog's OTLP traces intake, while the trace agent URL is ignored.
+    /// They can still be disabled with [`trace_enabled`](Config::trace_enabled).
+    ///
+    /// Can also be set via the `DD_API_KEY` environment variable.
+    ///
+    /// Defaults to `None`, which disables agentless mode.
+    pub api_key: Option<ApiKey>,
+
+    /// The Datadog site to send data to in agentless mode, e.g. `datadoghq.eu`.
+    ///
+    /// Can also be set via the `DD_SITE` environment variable.
+    ///
+    /// Defaults to `datadoghq.com`.
+    pub site: String,
+
+    /// Whether to emit JSON logs with trace correlation to stdout, which requires tracing to be
+    /// enabled, and to ship logs in agentless mode.
+    ///
+    /// Can also be set via the `DD_LOGS_INJECTION` environment variable.
+    ///
+    /// Defaults to `true` for the [`Production`](Profile::Production) profile only.
+    pub logs_injection: bool,
+
+    /// Whether to send metrics. When disabled, [`StatsD`](crate::statsd::StatsD) discards all
+    /// metrics without opening a socket.
+    ///
+    /// Can also be set via the `DD_METRICS_ENABLED` environment variable.
+    ///
+    /// Defaults to `true`, except for the [`Test`](Profile::Test) profile.
+    pub metrics_enabled: bool,
+
+    /// The maximum number of distinct tag sets per metric name, beyond which
+    /// [`StatsD`](crate::statsd::StatsD) replaces new tag values with `other`, to avoid an
+    /// explosion of custom metrics from tags like IDs. `0` disables the limit.
+    ///
+    /// Can also be set via the `DD_METRICS_CARDINALITY_LIMIT` environment variable.
+    ///
+    /// Defaults to `0`, as tracking tag sets costs memory and locking on every metric.
+    pub metrics_cardinality_limit: usize,
+
+    /// How often [`StatsD`](crate::statsd::StatsD) sends counts, gauges and sets that it
+    /// aggregates in memory, instead of sending every call as a datagram. Other metrics are
+    /// buffered until then too, and all are batched into as few datagrams as possible.
+    /// [`Duration::ZERO`] disables aggregation.
+    ///
+    /// Keep this at most the agent's flush interval of 10 seconds: with a longer interval, a
+    /// whole interval's count lands in a single agent bucket, making rates spiky, and gauges are
+    /// missing from the buckets in between.
+    ///
+    /// Can also be set via the `DD_METRICS_AGGREGATION_INTERVAL` environment variable, in
+    /// seconds.
+    ///
+    /// Defaults to zero.
+    pub metrics_aggregation_interval: Duration,
+
+    /// Whether to print human-readable logs to stdout.
+    ///
+    /// Can also be set via the `DD_LOGS_PRETTY` environment variable.
+    ///
+    /// Defaults to `true` for the [`Development`](Profile::Development) profile only.
+    pub pretty_logs: bool,
+
+    /// Where each value that is not a default came from, by field.
+    sources: BTreeMap<String, ConfigSource>,
 }
 
 impl Config {
@@ -65,36 +200,585 @@ impl Config {
     pub fn builder() -> ConfigBuilder {
         ConfigBuilder::default()
     }
+
+    /// Returns every effective setting along with where it came from, e.g. to find out why traces
+    /// are not sent.
+    ///
+    /// The sources are those at the time of [`ConfigBuilder::build`], so they do not reflect later
+    /// changes to the fields. [`Tracer`](crate::tracing::Tracer) logs this report at startup.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::Config;
+    ///
+    /// let config = Config::builder().service("payments").build().unwrap();
+    /// let service = &config.report().settings[0];
+    /// assert_eq!(service.name, "service");
+    /// assert_eq!(service.value.as_deref(), Some("payments"));
+    /// ```
+    pub fn report(&self) -> ConfigReport {
+        let source = |field: &str| {
+            self.sources
+                .get(field)
+                .copied()
+                .unwrap_or(ConfigSource::Default)
+        };
+        let setting = |name: &str, value: Option<&str>| ConfigSetting {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            source: source(name),
+        };
+
+        let trace_enabled = ConfigSetting {
+            name: String::from("trace_enabled"),
+            value: Some(self.trace_enabled.to_string()),
+            source: match self.sources.get("trace_enabled") {
+                Some(source) => *source,
+                None if self.trace_agent_url.is_none() && self.trace_enabled => source("api_key"),
+                None => source("trace_agent_url"),
+            },
+        };
+
+        let profile = ConfigSetting {
+            name: String::from("profile"),
+            value: Some(self.profile.to_string()),
+            source: match self.sources.get("profile") {
+                Some(source) => *source,
+                None => source("env"),
+            },
+        };
+
+        let mut settings = vec![
+            setting("service", Some(&self.service)),
+            setting("env", Some(&self.env)),
+            profile,
+            setting("version", Some(&self.version)),
+            trace_enabled,
+            setting("trace_agent_url", self.trace_agent_url.as_deref()),
+            setting(
+                "trace_sample_rate",
+                Some(&self.trace_sample_rate.to_string()),
+            ),
+            setting("metrics_agent_url", Some(&self.metrics_agent_url)),
+        ];
+        settings.extend(
+            self.tags
+                .iter()
+                .map(|(key, value)| setting(&format!("tags.{key}"), Some(value))),
+        );
+        settings.extend([
+            setting("git_repository_url", self.git_repository_url.as_deref()),
+            setting("git_commit_sha", self.git_commit_sha.as_deref()),
+            setting("log_filter", Some(&self.log_filter)),
+            setting("api_key", self.api_key.as_ref().map(|_| "***")),
+            setting("site", Some(&self.site)),
+            setting("logs_injection", Some(&self.logs_injection.to_string())),
+            setting("metrics_enabled", Some(&self.metrics_enabled.to_string())),
+            setting(
+                "metrics_cardinality_limit",
+                Some(&self.metrics_cardinality_limit.to_string()),
+            ),
+            setting(
+                "metrics_aggregation_interval",
+                Some(&format!("{}s", self.metrics_aggregation_interval.as_secs())),
+            ),
+            setting("pretty_logs", Some(&self.pretty_logs.to_string())),
+        ]);
+
+        ConfigReport { settings }
+    }
+}
+
+/// The effective settings of a [`Config`], see [`Config::report`].
+///
+/// With the `serde` feature, this can be serialized, e.g. to JSON.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct ConfigReport {
+    /// The settings, in the order of the fields of [`Config`], with tags as `tags.<key>`.
+    pub settings: Vec<ConfigSetting>,
+}
+
+impl Display for ConfigReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, setting) in self.settings.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{setting}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An effective setting of a [`Config`], see [`Config::report`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct ConfigSetting {
+    /// The name of the field, e.g. `metrics_agent_url`, or `tags.<key>` for tags.
+    pub name: String,
+    /// The value, or `None` if unset.
+    pub value: Option<String>,
+    /// Where the value came from.
+    pub source: ConfigSource,
+}
+
+impl Display for ConfigSetting {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={value} from {}", self.name, self.source),
+            None => write!(f, "{} unset from {}", self.name, self.source),
+        }
+    }
 }
 
 /// Builder to construct a [`Config`].
+///
+/// Each value is taken from the first of these sources that sets it:
+///
+/// 1. explicit calls to the builder's setters
+/// 2. `DD_` environment variables
+/// 3. config files, see [`ConfigBuilder::merge_file`] and [`ConfigBuilder::merge`]
+/// 4. [build metadata](ConfigBuilder::from_build_info)
+/// 5. defaults
+///
+/// Tags are merged across all sources instead, with the same precedence for tags with the same
+/// key.
+///
+/// Besides our own `DD_TRACE_AGENT_URL` and `DD_METRICS_AGENT_URL`, the agent URLs are resolved
+/// from the standard variables set by the Datadog Helm chart and admission controller, in this
+/// order:
+///
+/// Traces:
+///
+/// 1. `DD_TRACE_ENABLED=false` disables tracing, regardless of other variables
+/// 2. `DD_TRACE_AGENT_URL`, as `host:port`, `http://host:port` or `unix:///path`
+/// 3. `DD_APM_RECEIVER_SOCKET`, as a Unix socket path
+/// 4. `DD_AGENT_HOST` and `DD_TRACE_AGENT_PORT`, defaulting to `localhost` and `8126` if only one
+///    of them is set
+/// 5. `localhost:8126` if `DD_TRACE_ENABLED=true`, or tracing stays disabled otherwise
+///
+/// Metrics:
+///
+/// 1. `DD_METRICS_AGENT_URL`
+/// 2. `DD_DOGSTATSD_URL`, as `udp://host:port` or `unix:///path`
+/// 3. `DD_DOGSTATSD_SOCKET`, as a Unix datagram socket path
+/// 4. `DD_DOGSTATSD_HOST` or `DD_AGENT_HOST`, and `DD_DOGSTATSD_PORT`, defaulting to `localhost`
+///    and `8125`
 pub struct ConfigBuilder {
-    service: String,
-    env: String,
-    version: String,
-    trace_agent_url: Option<String>,
-    metrics_agent_url: String,
+    /// Values set through the builder's setters.
+    explicit: ConfigFile,
+    /// Values from environment variables.
+    env: ConfigFile,
+    /// Values from config files.
+    file: ConfigFile,
+    /// Values from build metadata.
+    build_info: ConfigFile,
+    /// The environment variables that values in `env` were read from, by field.
+    env_vars: BTreeMap<String, &'static str>,
+    /// Environment variables that could not be parsed.
+    env_issues: Vec<ConfigIssue>,
 }
 
 impl Default for ConfigBuilder {
     fn default() -> Self {
-        Self {
-            service: env::var("DD_SERVICE").unwrap_or_else(|_| String::from("unknown")),
-            env: env::var("DD_ENV").unwrap_or_else(|_| String::from("development")),
-            version: env::var("DD_VERSION").unwrap_or_else(|_| String::from("unknown")),
-            trace_agent_url: env::var("DD_TRACE_AGENT_URL").ok(),
-            metrics_agent_url: env::var("DD_METRICS_AGENT_URL")
-                .unwrap_or_else(|_| String::from("localhost:8125")),
-        }
+        Self::from_env(env_var)
+    }
+}
+
+/// A partial [`Config`], as loaded from a config file.
+///
+/// All fields are optional, and fall back to environment variables and defaults as described in
+/// [`ConfigBuilder`]. With the `serde` feature, this can be deserialized, e.g. from a section of a
+/// service's own config file, and applied with [`ConfigBuilder::merge`].
+///
+/// # Examples
+///
+/// A TOML file for [`ConfigBuilder::merge_file`]:
+///
+/// ```toml
+/// service = "payments"
+/// env = "production"
+/// trace_agent_url = "unix:///var/run/datadog/apm.socket"
+/// trace_sample_rate = 0.5
+/// log_filter = "info,sqlx=warn"
+///
+/// [profiles]
+/// local = "development"
+/// sandbox = "production"
+///
+/// [tags]
+/// team = "payments"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+#[non_exhaustive]
+pub struct ConfigFile {
+    /// See [`Config::service`].
+    pub service: Option<String>,
+    /// See [`Config::env`].
+    pub env: Option<String>,
+    /// See [`Config::version`].
+    pub version: Option<String>,
+    /// See [`Config::profile`].
+    pub profile: Option<Profile>,
+    /// Profiles by env, in addition to the default mapping, see
+    /// [`ConfigBuilder::profile_for_env`].
+    pub profiles: BTreeMap<String, Profile>,
+    /// Whether to send traces, defaulting to `localhost:8126` if no trace agent URL is set.
+    pub trace_enabled: Option<bool>,
+    /// See [`Config::trace_agent_url`].
+    pub trace_agent_url: Option<String>,
+    /// See [`Config::trace_sample_rate`].
+    pub trace_sample_rate: Option<f64>,
+    /// See [`Config::metrics_agent_url`].
+    pub metrics_agent_url: Option<String>,
+    /// See [`Config::tags`].
+    pub tags: BTreeMap<String, String>,
+    /// See [`Config::git_repository_url`].
+    pub git_repository_url: Option<String>,
+    /// See [`Config::git_commit_sha`].
+    pub git_commit_sha: Option<String>,
+    /// See [`Config::log_filter`].
+    pub log_filter: Option<String>,
+    /// See [`Config::api_key`].
+    pub api_key: Option<ApiKey>,
+    /// See [`Config::site`].
+    pub site: Option<String>,
+    /// See [`Config::logs_injection`].
+    pub logs_injection: Option<bool>,
+    /// See [`Config::metrics_enabled`].
+    pub metrics_enabled: Option<bool>,
+    /// See [`Config::metrics_cardinality_limit`].
+    pub metrics_cardinality_limit: Option<usize>,
+    /// See [`Config::metrics_aggregation_interval`], in seconds in config files.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_secs"))]
+    pub metrics_aggregation_interval: Option<Duration>,
+    /// See [`Config::pretty_logs`].
+    pub pretty_logs: Option<bool>,
+}
+
+impl ConfigFile {
+    /// Overrides values in `self` with those set in `other`.
+    fn merge(&mut self, other: ConfigFile) {
+        let ConfigFile {
+            service,
+            env,
+            version,
+            profile,
+            profiles,
+            trace_enabled,
+            trace_agent_url,
+            trace_sample_rate,
+            metrics_agent_url,
+            tags,
+            git_repository_url,
+            git_commit_sha,
+            log_filter,
+            api_key,
+            site,
+            logs_injection,
+            metrics_enabled,
+            metrics_cardinality_limit,
+            metrics_aggregation_interval,
+            pretty_logs,
+        } = other;
+
+        self.service = service.or(self.service.take());
+        self.env = env.or(self.env.take());
+        self.version = version.or(self.version.take());
+        self.profile = profile.or(self.profile);
+        self.profiles.extend(profiles);
+        self.trace_enabled = trace_enabled.or(self.trace_enabled);
+        self.trace_agent_url = trace_agent_url.or(self.trace_agent_url.take());
+        self.trace_sample_rate = trace_sample_rate.or(self.trace_sample_rate);
+        self.metrics_agent_url = metrics_agent_url.or(self.metrics_agent_url.take());
+        self.tags.extend(tags);
+        self.git_repository_url = git_repository_url.or(self.git_repository_url.take());
+        self.git_commit_sha = git_commit_sha.or(self.git_commit_sha.take());
+        self.log_filter = log_filter.or(self.log_filter.take());
+        self.api_key = api_key.or(self.api_key.take());
+        self.site = site.or(self.site.take());
+        self.logs_injection = logs_injection.or(self.logs_injection);
+        self.metrics_enabled = metrics_enabled.or(self.metrics_enabled);
+        self.metrics_cardinality_limit =
+            metrics_cardinality_limit.or(self.metrics_cardinality_limit);
+        self.metrics_aggregation_interval =
+            metrics_aggregation_interval.or(self.metrics_aggregation_interval);
+        self.pretty_logs = pretty_logs.or(self.pretty_logs);
+    }
+
+    /// Returns the names of the fields that are set, with tags as `tags.<key>`.
+    fn set_fields(&self) -> Vec<String> {
+        let fields = [
+            ("service", self.service.is_some()),
+            ("env", self.env.is_some()),
+            ("version", self.version.is_some()),
+            ("profile", self.profile.is_some()),
+            ("trace_enabled", self.trace_enabled.is_some()),
+            ("trace_agent_url", self.trace_agent_url.is_some()),
+            ("trace_sample_rate", self.trace_sample_rate.is_some()),
+            ("metrics_agent_url", self.metrics_agent_url.is_some()),
+            ("git_repository_url", self.git_repository_url.is_some()),
+            ("git_commit_sha", self.git_commit_sha.is_some()),
+            ("log_filter", self.log_filter.is_some()),
+            ("api_key", self.api_key.is_some()),
+            ("site", self.site.is_some()),
+            ("logs_injection", self.logs_injection.is_some()),
+            ("metrics_enabled", self.metrics_enabled.is_some()),
+            (
+                "metrics_cardinality_limit",
+                self.metrics_cardinality_limit.is_some(),
+            ),
+            (
+                "metrics_aggregation_interval",
+                self.metrics_aggregation_interval.is_some(),
+            ),
+            ("pretty_logs", self.pretty_logs.is_some()),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(field, _)| field.to_string())
+            .chain(self.tags.keys().map(|key| format!("tags.{key}")))
+            .collect()
+    }
+}
+
+/// Parses comma- or space-separated `key:value` tags, skipping entries without a value.
+fn parse_tags(tags: &str) -> BTreeMap<String, String> {
+    tags.split([',', ' '])
+        .filter_map(|tag| tag.trim().split_once(':'))
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Deserializes a duration from a number of seconds.
+#[cfg(feature = "serde")]
+fn deserialize_secs<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs: Option<u64> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(secs.map(Duration::from_secs))
+}
+
+/// Parses a boolean environment variable, where anything but `false` and `0` is true.
+fn parse_bool(value: &str) -> bool {
+    !matches!(value, "false" | "0")
+}
+
+/// Returns the value of a non-empty environment variable.
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Joins a host and port into an address, bracketing IPv6 hosts.
+fn host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Resolves an address from host and port environment variables, defaulting to `localhost` and
+/// `default_port` if only one of them is set.
+///
+/// Returns the address with the name of the variable it came from: the port variable if it is
+/// set to an invalid port or is the only one set, and the host variable otherwise.
+fn host_port_from_env(
+    var: &impl Fn(&str) -> Option<String>,
+    host_vars: &[&'static str],
+    port_var: &'static str,
+    default_port: &str,
+) -> Option<(String, &'static str)> {
+    let host = host_vars
+        .iter()
+        .find_map(|&name| var(name).map(|host| (host, name)));
+    let port = var(port_var);
+    let source = match (&host, &port) {
+        (_, Some(port)) if port.parse::<u16>().is_err() => port_var,
+        (Some((_, name)), _) => *name,
+        (None, Some(_)) => port_var,
+        (None, None) => return None,
+    };
+
+    let address = host_port(
+        host.as_ref().map_or("localhost", |(host, _)| host),
+        port.as_deref().unwrap_or(default_port),
+    );
+    Some((address, source))
+}
+
+/// Resolves the trace agent URL from environment variables, see [`ConfigBuilder`].
+///
+/// Returns the URL with the name of the variable it came from.
+fn trace_agent_url_from_env(
+    var: &impl Fn(&str) -> Option<String>,
+) -> Option<(String, &'static str)> {
+    if let Some(url) = var("DD_TRACE_AGENT_URL") {
+        let url = url.strip_prefix("http://").unwrap_or(&url);
+        return Some((url.trim_end_matches('/').to_string(), "DD_TRACE_AGENT_URL"));
+    }
+    if let Some(path) = var("DD_APM_RECEIVER_SOCKET") {
+        return Some((format!("unix://{path}"), "DD_APM_RECEIVER_SOCKET"));
     }
+
+    host_port_from_env(var, &["DD_AGENT_HOST"], "DD_TRACE_AGENT_PORT", "8126")
+}
+
+/// Resolves the metrics agent URL from environment variables, see [`ConfigBuilder`].
+///
+/// Returns the URL with the name of the variable it came from.
+fn metrics_agent_url_from_env(
+    var: &impl Fn(&str) -> Option<String>,
+) -> Option<(String, &'static str)> {
+    if let Some(url) = var("DD_METRICS_AGENT_URL") {
+        return Some((url, "DD_METRICS_AGENT_URL"));
+    }
+    if let Some(url) = var("DD_DOGSTATSD_URL") {
+        let url = url.strip_prefix("udp://").unwrap_or(&url).to_string();
+        return Some((url, "DD_DOGSTATSD_URL"));
+    }
+    if let Some(path) = var("DD_DOGSTATSD_SOCKET") {
+        return Some((format!("unix://{path}"), "DD_DOGSTATSD_SOCKET"));
+    }
+
+    host_port_from_env(
+        var,
+        &["DD_DOGSTATSD_HOST", "DD_AGENT_HOST"],
+        "DD_DOGSTATSD_PORT",
+        "8125",
+    )
 }
 
 impl ConfigBuilder {
+    /// Creates a builder with values from the environment, as looked up by `var`.
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut env_vars = BTreeMap::new();
+        let mut read = |field: &str, name: &'static str| {
+            let value = var(name);
+            if value.is_some() {
+                env_vars.insert(field.to_string(), name);
+            }
+            value
+        };
+
+        let mut env = ConfigFile {
+            service: read("service", "DD_SERVICE"),
+            env: read("env", "DD_ENV"),
+            version: read("version", "DD_VERSION"),
+            profile: None,
+            profiles: BTreeMap::new(),
+            trace_enabled: read("trace_enabled", "DD_TRACE_ENABLED")
+                .map(|value| parse_bool(&value)),
+            trace_agent_url: None,
+            trace_sample_rate: None,
+            metrics_agent_url: None,
+            tags: read("tags", "DD_TAGS")
+                .map(|tags| parse_tags(&tags))
+                .unwrap_or_default(),
+            git_repository_url: read("git_repository_url", "DD_GIT_REPOSITORY_URL"),
+            git_commit_sha: read("git_commit_sha", "DD_GIT_COMMIT_SHA"),
+            log_filter: read("log_filter", "RUST_LOG"),
+            api_key: read("api_key", "DD_API_KEY").map(ApiKey),
+            site: read("site", "DD_SITE"),
+            logs_injection: read("logs_injection", "DD_LOGS_INJECTION")
+                .map(|value| parse_bool(&value)),
+            metrics_enabled: read("metrics_enabled", "DD_METRICS_ENABLED")
+                .map(|value| parse_bool(&value)),
+            metrics_cardinality_limit: None,
+            metrics_aggregation_interval: None,
+            pretty_logs: read("pretty_logs", "DD_LOGS_PRETTY").map(|value| parse_bool(&value)),
+        };
+
+        let mut env_issues = Vec::new();
+        let mut number = |field: &str, name: &'static str, kind, hint| -> Option<u64> {
+            let value = var(name)?;
+            match value.parse() {
+                Ok(number) => {
+                    env_vars.insert(field.to_string(), name);
+                    Some(number)
+                }
+                Err(_) => {
+                    env_issues.push(ConfigIssue {
+                        kind,
+                        field: field.to_string(),
+                        value,
+                        source: ConfigSource::Env(name),
+                        reason: "is not a number",
+                        hint,
+                    });
+                    None
+                }
+            }
+        };
+        env.metrics_cardinality_limit = number(
+            "metrics_cardinality_limit",
+            "DD_METRICS_CARDINALITY_LIMIT",
+            IssueKind::InvalidCardinalityLimit,
+            "use a number like `1000`, or `0` for no limit",
+        )
+        .map(|limit| limit as usize);
+        env.metrics_aggregation_interval = number(
+            "metrics_aggregation_interval",
+            "DD_METRICS_AGGREGATION_INTERVAL",
+            IssueKind::InvalidAggregationInterval,
+            "use a number of seconds like `10`, or `0` to disable aggregation",
+        )
+        .map(Duration::from_secs);
+        if let Some(value) = var("DD_TRACE_SAMPLE_RATE") {
+            match value.parse() {
+                Ok(rate) => {
+                    env.trace_sample_rate = Some(rate);
+                    env_vars.insert(String::from("trace_sample_rate"), "DD_TRACE_SAMPLE_RATE");
+                }
+                Err(_) => env_issues.push(ConfigIssue {
+                    kind: IssueKind::InvalidSampleRate,
+                    field: String::from("trace_sample_rate"),
+                    value,
+                    source: ConfigSource::Env("DD_TRACE_SAMPLE_RATE"),
+                    reason: "is not a number",
+                    hint: SAMPLE_RATE_HINT,
+                }),
+            }
+        }
+
+        for key in env.tags.keys() {
+            env_vars.insert(format!("tags.{key}"), "DD_TAGS");
+        }
+        if env.trace_enabled != Some(false) {
+            if let Some((url, name)) = trace_agent_url_from_env(&var) {
+                env.trace_agent_url = Some(url);
+                env_vars.insert(String::from("trace_agent_url"), name);
+            }
+        }
+        if let Some((url, name)) = metrics_agent_url_from_env(&var) {
+            env.metrics_agent_url = Some(url);
+            env_vars.insert(String::from("metrics_agent_url"), name);
+        }
+
+        Self {
+            explicit: ConfigFile::default(),
+            env,
+            file: ConfigFile::default(),
+            build_info: ConfigFile::default(),
+            env_vars,
+            env_issues,
+        }
+    }
+
     /// Sets the `service` for the config.
     ///
     /// By default, this is the value of `DD_SERVICE`, or otherwise `"unknown"`.
     pub fn service(mut self, service: impl Into<String>) -> Self {
-        self.service = service.into();
+        self.explicit.service = Some(service.into());
         self
     }
 
@@ -102,7 +786,38 @@ impl ConfigBuilder {
     ///
     /// By default, this is the value of `DD_ENV`, or otherwise `"development"`.
     pub fn env(mut self, env: impl Into<String>) -> Self {
-        self.env = env.into();
+        self.explicit.env = Some(env.into());
+        self
+    }
+
+    /// Sets the `profile` for the config, regardless of the env.
+    ///
+    /// By default, this is the profile that the env maps to, see
+    /// [`ConfigBuilder::profile_for_env`].
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.explicit.profile = Some(profile);
+        self
+    }
+
+    /// Maps an env to a profile, for envs that the default mapping doesn't cover.
+    ///
+    /// By default, `development`, `dev` and `local` map to [`Profile::Development`], `test` maps
+    /// to [`Profile::Test`], and every other env to [`Profile::Production`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::{Config, config::Profile};
+    ///
+    /// let config = Config::builder()
+    ///   .env("sandbox")
+    ///   .profile_for_env("sandbox", Profile::Test)
+    ///   .build()
+    ///   .unwrap();
+    /// assert_eq!(config.profile, Profile::Test);
+    /// ```
+    pub fn profile_for_env(mut self, env: impl Into<String>, profile: Profile) -> Self {
+        self.explicit.profiles.insert(env.into(), profile);
         self
     }
 
@@ -110,115 +825,852 @@ impl ConfigBuilder {
     ///
     /// By default, this is the value of `DD_VERSION`, or otherwise `"unknown"`.
     pub fn version(mut self, version: impl Into<String>) -> Self {
-        self.version = version.into();
+        self.explicit.version = Some(version.into());
         self
     }
 
     /// Sets the `trace_agent_url` for the config.
     ///
-    /// The format is `host:port`.
+    /// The format is `host:port`, or `unix:///path/to/apm.socket` for a Unix socket.
     ///
     /// If this value is not set, tracing will be disabled.
     ///
-    /// By default, this is the value of `DD_TRACE_AGENT_URL`, or otherwise `None`.
+    /// By default, this is resolved from environment variables as documented on
+    /// [`ConfigBuilder`], or otherwise `None`.
     pub fn trace_agent_url(mut self, trace_agent_url: Option<impl Into<String>>) -> Self {
-        self.trace_agent_url = trace_agent_url.map(Into::into);
+        self.explicit.trace_agent_url = trace_agent_url.map(Into::into);
+        self.explicit.trace_enabled = Some(self.explicit.trace_agent_url.is_some());
+        self
+    }
+
+    /// Enables or disables tracing, keeping the `trace_agent_url` if any. If enabled without a
+    /// `trace_agent_url` outside of agentless mode, traces are sent to `localhost:8126`.
+    ///
+    /// By default, this is the value of `DD_TRACE_ENABLED`, or otherwise whether a
+    /// `trace_agent_url` or an `api_key` is set.
+    pub fn trace_enabled(mut self, enabled: bool) -> Self {
+        self.explicit.trace_enabled = Some(enabled);
+        self
+    }
+
+    /// Sets the `trace_sample_rate` for the config, from `0.0` to `1.0`.
+    ///
+    /// By default, this is the value of `DD_TRACE_SAMPLE_RATE`, or otherwise `1.0`.
+    pub fn trace_sample_rate(mut self, rate: f64) -> Self {
+        self.explicit.trace_sample_rate = Some(rate);
         self
     }
 
     /// Sets the `metrics_agent_url` for the config.
     ///
-    /// The format is `host:port`.
+    /// The format is `host:port`, or `unix:///path/to/dsd.socket` for a Unix datagram socket.
+    /// Stream sockets can be used with `unixstream:///path/to/dsd.socket`.
     ///
-    /// By default, this is the value of `DD_METRICS_AGENT_URL`, or otherwise `"localhost:8126"`.
+    /// By default, this is resolved from environment variables as documented on
+    /// [`ConfigBuilder`], or otherwise `"localhost:8125"`.
     pub fn metrics_agent_url(mut self, metrics_agent_url: impl Into<String>) -> Self {
-        self.metrics_agent_url = metrics_agent_url.into();
+        self.explicit.metrics_agent_url = Some(metrics_agent_url.into());
+        self
+    }
+
+    /// Sets the `git_repository_url` for the config.
+    ///
+    /// By default, this is the value of `DD_GIT_REPOSITORY_URL`, or otherwise `None`.
+    pub fn git_repository_url(mut self, git_repository_url: impl Into<String>) -> Self {
+        self.explicit.git_repository_url = Some(git_repository_url.into());
+        self
+    }
+
+    /// Sets the `git_commit_sha` for the config.
+    ///
+    /// By default, this is the value of `DD_GIT_COMMIT_SHA`, or otherwise `None`.
+    pub fn git_commit_sha(mut self, git_commit_sha: impl Into<String>) -> Self {
+        self.explicit.git_commit_sha = Some(git_commit_sha.into());
+        self
+    }
+
+    /// Sets the `log_filter` for the config.
+    ///
+    /// By default, this is the value of `RUST_LOG`, or otherwise `"info"`.
+    pub fn log_filter(mut self, log_filter: impl Into<String>) -> Self {
+        self.explicit.log_filter = Some(log_filter.into());
         self
     }
 
+    /// Sets the `api_key` for the config, enabling agentless mode.
+    ///
+    /// By default, this is the value of `DD_API_KEY`, or otherwise `None`.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.explicit.api_key = Some(ApiKey(api_key.into()));
+        self
+    }
+
+    /// Sets the `site` for the config.
+    ///
+    /// By default, this is the value of `DD_SITE`, or otherwise `"datadoghq.com"`.
+    pub fn site(mut self, site: impl Into<String>) -> Self {
+        self.explicit.site = Some(site.into());
+        self
+    }
+
+    /// Sets `logs_injection` for the config.
+    ///
+    /// By default, this is the value of `DD_LOGS_INJECTION`, or otherwise `true` for the
+    /// [`Production`](Profile::Production) profile only.
+    pub fn logs_injection(mut self, enabled: bool) -> Self {
+        self.explicit.logs_injection = Some(enabled);
+        self
+    }
+
+    /// Sets `metrics_enabled` for the config.
+    ///
+    /// By default, this is the value of `DD_METRICS_ENABLED`, or otherwise `true` except for the
+    /// [`Test`](Profile::Test) profile.
+    pub fn metrics_enabled(mut self, enabled: bool) -> Self {
+        self.explicit.metrics_enabled = Some(enabled);
+        self
+    }
+
+    /// Sets `metrics_cardinality_limit` for the config, where `0` disables the limit.
+    ///
+    /// By default, this is the value of `DD_METRICS_CARDINALITY_LIMIT`, or otherwise `0`.
+    pub fn metrics_cardinality_limit(mut self, limit: usize) -> Self {
+        self.explicit.metrics_cardinality_limit = Some(limit);
+        self
+    }
+
+    /// Sets `metrics_aggregation_interval` for the config, where [`Duration::ZERO`] disables
+    /// aggregation. Intervals longer than the agent's 10 second flush are not recommended, see
+    /// [`Config::metrics_aggregation_interval`].
+    ///
+    /// By default, this is the value of `DD_METRICS_AGGREGATION_INTERVAL` in seconds, or
+    /// otherwise zero.
+    pub fn metrics_aggregation_interval(mut self, interval: Duration) -> Self {
+        self.explicit.metrics_aggregation_interval = Some(interval);
+        self
+    }
+
+    /// Sets `pretty_logs` for the config.
+    ///
+    /// By default, this is the value of `DD_LOGS_PRETTY`, or otherwise `true` for the
+    /// [`Development`](Profile::Development) profile only.
+    pub fn pretty_logs(mut self, enabled: bool) -> Self {
+        self.explicit.pretty_logs = Some(enabled);
+        self
+    }
+
+    /// Fills the service, version and git metadata from build metadata, as captured by
+    /// [`build_info!`](crate::build_info).
+    ///
+    /// Setters, environment variables and config files take precedence over build metadata.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::{Config, build_info};
+    ///
+    /// Config::builder()
+    ///   .from_build_info(build_info!())
+    ///   .env("production")
+    ///   .build();
+    /// ```
+    pub fn from_build_info(mut self, build_info: BuildInfo) -> Self {
+        self.build_info = ConfigFile {
+            service: Some(build_info.name.to_string()),
+            version: Some(build_info.version.to_string()),
+            git_repository_url: build_info.git_repository_url.map(str::to_string),
+            git_commit_sha: build_info.git_commit_sha.map(str::to_string),
+            ..ConfigFile::default()
+        };
+        self
+    }
+
+    /// Adds a tag to all metrics and traces, replacing any tag with the same key, e.g. from
+    /// `DD_TAGS`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::Config;
+    ///
+    /// Config::builder()
+    ///   .tag("team", "payments")
+    ///   .tag("region", "ap-northeast-1")
+    ///   .build();
+    /// ```
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let _ = self.explicit.tags.insert(key.into(), value.into());
+        self
+    }
+
+    /// Merges values from a config file section, e.g. one deserialized as part of the service's
+    /// own config.
+    ///
+    /// Values from later merges take precedence over earlier ones, while setters and environment
+    /// variables take precedence over all of them.
+    pub fn merge(mut self, file: ConfigFile) -> Self {
+        self.file.merge(file);
+        self
+    }
+
+    /// Merges values from a TOML or YAML config file, see [`ConfigFile`] for the format.
+    ///
+    /// The format is detected from the file extension: `.toml`, or `.yaml` or `.yml`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use komoju_datadog::Config;
+    ///
+    /// let config = Config::builder()
+    ///   .merge_file("config/datadog.toml")
+    ///   .expect("failed to load config file")
+    ///   .build()
+    ///   .expect("invalid config");
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn merge_file(self, path: impl AsRef<Path>) -> Result<Self, ConfigFileError> {
+        let path = path.as_ref();
+        let parse: fn(&str) -> Result<ConfigFile, ConfigFileError> =
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("toml") => |contents| toml::from_str(contents).map_err(ConfigFileError::Toml),
+                Some("yaml" | "yml") => {
+                    |contents| serde_yaml::from_str(contents).map_err(ConfigFileError::Yaml)
+                }
+                _ => return Err(ConfigFileError::UnsupportedFormat),
+            };
+        let contents = std::fs::read_to_string(path).map_err(ConfigFileError::Io)?;
+        Ok(self.merge(parse(&contents)?))
+    }
+
     /// Consumes the builder, returning the constructed `Config`.
-    pub fn build(self) -> Result<Config, BuilderError> {
-        self.validate()?;
+    ///
+    /// All invalid values are reported at once, see [`BuilderError::issues`].
+    pub fn build(mut self) -> Result<Config, BuilderError> {
+        // Unparsable environment variables only matter if nothing else overrides them.
+        let overridden = self.explicit.set_fields();
+        let mut issues = std::mem::take(&mut self.env_issues);
+        issues.retain(|issue| !overridden.contains(&issue.field));
+
+        let config = self.resolve();
+        issues.extend(validate(&config));
+        if issues.is_empty() {
+            Ok(config)
+        } else {
+            Err(BuilderError { issues })
+        }
+    }
 
+    /// Resolves the configuration from all sources, in order of precedence.
+    fn resolve(self) -> Config {
         let Self {
-            service,
+            explicit,
             env,
-            version,
-            trace_agent_url,
-            metrics_agent_url,
+            file,
+            build_info,
+            env_vars,
+            env_issues: _,
         } = self;
 
-        Ok(Config {
-            service,
+        let mut resolved = ConfigFile::default();
+        let mut sources = BTreeMap::new();
+        let mut apply = |layer: ConfigFile, source: &dyn Fn(&str) -> ConfigSource| {
+            for field in layer.set_fields() {
+                let source = source(&field);
+                sources.insert(field, source);
+            }
+            resolved.merge(layer);
+        };
+        apply(build_info, &|_| ConfigSource::BuildInfo);
+        apply(file, &|_| ConfigSource::File);
+        apply(env, &|field| {
+            env_vars
+                .get(field)
+                .map_or(ConfigSource::Default, |name| ConfigSource::Env(name))
+        });
+        apply(explicit, &|_| ConfigSource::Builder);
+
+        let trace_agent_url = match resolved.trace_enabled {
+            Some(false) => None,
+            Some(true) => resolved
+                .trace_agent_url
+                .or_else(|| Some(String::from("localhost:8126"))),
+            None => resolved.trace_agent_url,
+        };
+
+        let env = resolved.env.unwrap_or_else(|| String::from("development"));
+        let profile = resolved.profile.unwrap_or_else(|| {
+            resolved
+                .profiles
+                .get(&env)
+                .copied()
+                .unwrap_or_else(|| Profile::for_env(&env))
+        });
+
+        Config {
+            service: resolved.service.unwrap_or_else(|| String::from("unknown")),
             env,
-            version,
+            profile,
+            version: resolved.version.unwrap_or_else(|| String::from("unknown")),
+            trace_enabled: trace_agent_url.is_some()
+                || (cfg!(feature = "agentless")
+                    && resolved.api_key.is_some()
+                    && resolved.trace_enabled != Some(false)),
             trace_agent_url,
-            metrics_agent_url,
-        })
+            trace_sample_rate: resolved.trace_sample_rate.unwrap_or(1.0),
+            metrics_agent_url: resolved
+                .metrics_agent_url
+                .unwrap_or_else(|| String::from("localhost:8125")),
+            tags: resolved.tags.into_iter().collect(),
+            git_repository_url: resolved.git_repository_url,
+            git_commit_sha: resolved.git_commit_sha,
+            log_filter: resolved.log_filter.unwrap_or_else(|| String::from("info")),
+            api_key: resolved.api_key,
+            site: resolved
+                .site
+                .unwrap_or_else(|| String::from("datadoghq.com")),
+            logs_injection: resolved
+                .logs_injection
+                .unwrap_or(profile == Profile::Production),
+            metrics_enabled: resolved.metrics_enabled.unwrap_or(profile != Profile::Test),
+            metrics_cardinality_limit: resolved.metrics_cardinality_limit.unwrap_or(0),
+            metrics_aggregation_interval: resolved.metrics_aggregation_interval.unwrap_or_default(),
+            pretty_logs: resolved
+                .pretty_logs
+                .unwrap_or(profile == Profile::Development),
+            sources,
+        }
+    }
+}
+
+/// How to fix an invalid trace sample rate.
+const SAMPLE_RATE_HINT: &str = "use a rate from `0.0` to `1.0`, like `0.1` to keep 10% of traces";
+
+/// Characters that would break the DogStatsD protocol or `DD_TAGS` parsing in tags.
+fn has_tag_separators(value: &str) -> bool {
+    value
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, ',' | '|' | '#'))
+}
+
+/// Validates a resolved configuration, returning all issues found.
+fn validate(config: &Config) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    let mut issue = |kind, field: &str, value: &str, reason, hint| {
+        issues.push(ConfigIssue {
+            kind,
+            field: field.to_string(),
+            value: value.to_string(),
+            source: config
+                .sources
+                .get(field)
+                .copied()
+                .unwrap_or(ConfigSource::Default),
+            reason,
+            hint,
+        });
+    };
+
+    if config.service.is_empty() {
+        issue(
+            IssueKind::InvalidServiceName,
+            "service",
+            &config.service,
+            "is empty",
+            "set `DD_SERVICE` or use a name like `payments`",
+        );
+    }
+
+    if config.env.is_empty() {
+        issue(
+            IssueKind::InvalidEnv,
+            "env",
+            &config.env,
+            "is empty",
+            "set `DD_ENV` or use a name like `production`",
+        );
+    } else if !config.env.chars().all(|c| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '-' | '.' | ':' | '/')
+    }) {
+        issue(
+            IssueKind::InvalidEnv,
+            "env",
+            &config.env,
+            "contains characters other than lowercase letters, digits, `_`, `-`, `.`, `:` and `/`",
+            "use a lowercase name like `production`",
+        );
     }
 
-    /// Validates the current configuration.
-    fn validate(&self) -> Result<(), BuilderError> {
-        if self.service.is_empty() {
-            return Err(BuilderError::InvalidServiceName);
+    if config.version.is_empty() || has_tag_separators(&config.version) {
+        issue(
+            IssueKind::InvalidVersion,
+            "version",
+            &config.version,
+            "is empty or contains whitespace, `,`, `|` or `#`",
+            "use a version like `1.2.3` or a git commit SHA",
+        );
+    }
+
+    for (key, value) in &config.tags {
+        let field = format!("tags.{key}");
+        let tag = format!("{key}:{value}");
+        if key.is_empty() {
+            issue(
+                IssueKind::InvalidTag,
+                &field,
+                &tag,
+                "has an empty key",
+                "use tags like `team:payments`",
+            );
+        } else if has_tag_separators(key) || has_tag_separators(value) {
+            issue(
+                IssueKind::InvalidTag,
+                &field,
+                &tag,
+                "contains whitespace, `,`, `|` or `#`",
+                "use tags like `team:payments`",
+            );
         }
+    }
+
+    if !(0.0..=1.0).contains(&config.trace_sample_rate) {
+        issue(
+            IssueKind::InvalidSampleRate,
+            "trace_sample_rate",
+            &config.trace_sample_rate.to_string(),
+            "is not between `0.0` and `1.0`",
+            SAMPLE_RATE_HINT,
+        );
+    }
+
+    if config.site.is_empty() {
+        issue(
+            IssueKind::InvalidSite,
+            "site",
+            &config.site,
+            "is empty",
+            "use a Datadog site like `datadoghq.com` or `datadoghq.eu`",
+        );
+    } else if config.site.contains(['/', ':']) || has_tag_separators(&config.site) {
+        issue(
+            IssueKind::InvalidSite,
+            "site",
+            &config.site,
+            "is not a plain host name",
+            "use a Datadog site like `datadoghq.com` or `datadoghq.eu`",
+        );
+    }
+
+    if config
+        .api_key
+        .as_ref()
+        .is_some_and(|api_key| api_key.0.is_empty())
+    {
+        issue(
+            IssueKind::InvalidApiKey,
+            "api_key",
+            "",
+            "is empty",
+            "set `DD_API_KEY` to an API key of the Datadog organization",
+        );
+    }
+
+    if let Err(reason) = AgentAddress::parse(&config.metrics_agent_url) {
+        issue(
+            IssueKind::InvalidMetricsAgentUrl,
+            "metrics_agent_url",
+            &config.metrics_agent_url,
+            reason,
+            "use `host:port`, or `unix:///path/to/dsd.socket` for a Unix socket",
+        );
+    }
 
-        let validate_url = |url: &str, error: BuilderError| -> Result<(), BuilderError> {
-            url::Url::parse(&format!("http://{url}"))
-                .map_err(|_| error)?
-                .port()
-                .ok_or(error)?;
-            Ok(())
+    if let Some(trace_agent_url) = &config.trace_agent_url {
+        let reason = match AgentAddress::parse(trace_agent_url) {
+            Ok(AgentAddress::Unixgram(_)) => {
+                Some("is a Unix datagram socket, which is not supported for traces")
+            }
+            Ok(_) => None,
+            Err(reason) => Some(reason),
         };
+        if let Some(reason) = reason {
+            issue(
+                IssueKind::InvalidTraceAgentUrl,
+                "trace_agent_url",
+                trace_agent_url,
+                reason,
+                "use `host:port`, or `unix:///path/to/apm.socket` for a Unix socket",
+            );
+        }
+    }
 
-        validate_url(
-            &self.metrics_agent_url,
-            BuilderError::InvalidMetricsAgentUrl,
-        )?;
+    issues
+}
 
-        if let Some(trace_agent_url) = &self.trace_agent_url {
-            validate_url(trace_agent_url, BuilderError::InvalidTraceAgentUrl)?;
+/// The kind of environment a service runs in, see [`Config::profile`].
+///
+/// Defaults of other fields are derived from the profile, so that envs with any name get the
+/// right behavior.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Profile {
+    /// Local development, with pretty logs and no log injection.
+    Development,
+    /// Automated tests, with neither pretty logs, log injection nor metrics.
+    Test,
+    /// Deployed services, with JSON logs for Datadog.
+    Production,
+}
+
+impl Profile {
+    /// Returns the profile of an env with the default mapping, see
+    /// [`ConfigBuilder::profile_for_env`].
+    pub fn for_env(env: &str) -> Self {
+        match env {
+            "development" | "dev" | "local" => Self::Development,
+            "test" => Self::Test,
+            _ => Self::Production,
         }
+    }
+}
 
-        Ok(())
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Development => "development",
+            Self::Test => "test",
+            Self::Production => "production",
+        })
+    }
+}
+
+/// A Datadog API key, see [`Config::api_key`].
+///
+/// The key is redacted in debug output.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(transparent))]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    /// Wraps an API key.
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self(api_key.into())
+    }
+
+    /// Returns the API key.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ApiKey(***)")
+    }
+}
+
+/// Build metadata of a service, as captured at compile time by [`build_info!`](crate::build_info).
+///
+/// See [`ConfigBuilder::from_build_info`].
+#[derive(Clone, Copy, Debug)]
+pub struct BuildInfo {
+    name: &'static str,
+    version: &'static str,
+    git_commit_sha: Option<&'static str>,
+    git_repository_url: Option<&'static str>,
+}
+
+impl BuildInfo {
+    /// Creates build metadata. Empty values are treated as unknown.
+    pub const fn new(
+        name: &'static str,
+        version: &'static str,
+        git_commit_sha: Option<&'static str>,
+        git_repository_url: Option<&'static str>,
+    ) -> Self {
+        Self {
+            name,
+            version,
+            git_commit_sha: non_empty(git_commit_sha),
+            git_repository_url: non_empty(git_repository_url),
+        }
+    }
+}
+
+/// Returns `None` for empty strings.
+const fn non_empty(value: Option<&'static str>) -> Option<&'static str> {
+    match value {
+        Some(value) if !value.is_empty() => Some(value),
+        _ => None,
+    }
+}
+
+/// Captures the [`BuildInfo`] of the calling crate at compile time.
+///
+/// This uses the package name and version, the `repository` from `Cargo.toml`, and the git commit
+/// SHA from the `DD_GIT_COMMIT_SHA` or `GIT_COMMIT_SHA` environment variables at build time.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::{Config, build_info};
+///
+/// let config = Config::builder()
+///   .from_build_info(build_info!())
+///   .build()
+///   .expect("invalid config");
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::config::BuildInfo::new(
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            match option_env!("DD_GIT_COMMIT_SHA") {
+                Some(sha) => Some(sha),
+                None => option_env!("GIT_COMMIT_SHA"),
+            },
+            Some(env!("CARGO_PKG_REPOSITORY")),
+        )
+    };
+}
+
+/// The address of a Datadog agent endpoint, parsed from an agent URL.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AgentAddress<'a> {
+    /// A `host:port` address.
+    Host(&'a str),
+    /// A Unix socket from a `unix://` URL, whose type depends on the endpoint.
+    Unix(&'a Path),
+    /// A Unix datagram socket from a `unixgram://` URL.
+    Unixgram(&'a Path),
+    /// A Unix stream socket from a `unixstream://` URL.
+    Unixstream(&'a Path),
+}
+
+impl<'a> AgentAddress<'a> {
+    /// Parses an agent URL, returning the reason if it is invalid.
+    pub(crate) fn parse(url: &'a str) -> Result<Self, &'static str> {
+        if url.is_empty() {
+            return Err("is empty");
+        }
+
+        if let Some((scheme, path)) = url.split_once("://") {
+            let address: fn(&'a Path) -> Self = match scheme {
+                "unix" => Self::Unix,
+                "unixgram" => Self::Unixgram,
+                "unixstream" => Self::Unixstream,
+                _ => return Err("has an unsupported scheme"),
+            };
+            let path = Path::new(path);
+            if !path.is_absolute() {
+                return Err("has a relative Unix socket path");
+            }
+            return Ok(address(path));
+        }
+
+        match url::Url::parse(&format!("http://{url}")) {
+            Ok(parsed) if parsed.port().is_some() => Ok(Self::Host(url)),
+            Ok(_) => Err("is missing a port"),
+            Err(url::ParseError::InvalidPort) => Err("has an invalid port"),
+            Err(url::ParseError::EmptyHost) => Err("is missing a host"),
+            Err(_) => Err("has an invalid host"),
+        }
     }
 }
 
 /// Errors that can occur during [`ConfigBuilder::build`].
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
+pub struct BuilderError {
+    issues: Vec<ConfigIssue>,
+}
+
+impl BuilderError {
+    /// Returns all issues found in the config, of which there is at least one.
+    pub fn issues(&self) -> &[ConfigIssue] {
+        &self.issues
+    }
+}
+
+impl Display for BuilderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid config")?;
+        for issue in &self.issues {
+            write!(f, "\n- {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for BuilderError {}
+
+/// An invalid value found by [`ConfigBuilder::build`].
+#[derive(Clone, Debug, PartialEq, Eq)]
 #[non_exhaustive]
-pub enum BuilderError {
+pub struct ConfigIssue {
+    /// The kind of issue.
+    pub kind: IssueKind,
+    /// The name of the field, e.g. `metrics_agent_url`, or `tags.<key>` for tags.
+    pub field: String,
+    /// The rejected value, or `key:value` for tags.
+    pub value: String,
+    /// Where the rejected value came from.
+    pub source: ConfigSource,
+    /// Why the value was rejected, e.g. `"is missing a port"`.
+    pub reason: &'static str,
+    /// How to fix the value.
+    pub hint: &'static str,
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: `{}` from {} {}; {}",
+            self.kind, self.value, self.source, self.reason, self.hint
+        )
+    }
+}
+
+/// The kinds of [`ConfigIssue`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IssueKind {
     /// The service name is invalid.
     InvalidServiceName,
+    /// The env name is invalid.
+    InvalidEnv,
+    /// The version is invalid.
+    InvalidVersion,
     /// The metrics agent URL is invalid.
     InvalidMetricsAgentUrl,
     /// The trace agent URL is invalid.
     InvalidTraceAgentUrl,
+    /// A tag is invalid.
+    InvalidTag,
+    /// The Datadog site is invalid.
+    InvalidSite,
+    /// The API key is invalid.
+    InvalidApiKey,
+    /// The metrics cardinality limit is invalid.
+    InvalidCardinalityLimit,
+    /// The metrics aggregation interval is invalid.
+    InvalidAggregationInterval,
+    /// The trace sample rate is invalid.
+    InvalidSampleRate,
 }
 
-impl Display for BuilderError {
+impl Display for IssueKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::InvalidServiceName => write!(f, "invalid service name"),
+            Self::InvalidEnv => write!(f, "invalid env"),
+            Self::InvalidVersion => write!(f, "invalid version"),
             Self::InvalidMetricsAgentUrl => write!(f, "invalid metrics agent URL"),
             Self::InvalidTraceAgentUrl => write!(f, "invalid trace agent URL"),
+            Self::InvalidTag => write!(f, "invalid tag"),
+            Self::InvalidSite => write!(f, "invalid site"),
+            Self::InvalidApiKey => write!(f, "invalid API key"),
+            Self::InvalidCardinalityLimit => write!(f, "invalid metrics cardinality limit"),
+            Self::InvalidAggregationInterval => write!(f, "invalid metrics aggregation interval"),
+            Self::InvalidSampleRate => write!(f, "invalid trace sample rate"),
         }
     }
 }
 
-impl Error for BuilderError {}
+/// Where a config value came from, see [`ConfigBuilder`] for their precedence.
+///
+/// With the `serde` feature, this serializes as e.g. `{"type": "env", "name": "DD_ENV"}`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(rename_all = "snake_case", tag = "type", content = "name")
+)]
+#[non_exhaustive]
+pub enum ConfigSource {
+    /// A setter of [`ConfigBuilder`].
+    Builder,
+    /// The environment variable with the given name.
+    Env(&'static str),
+    /// A config file, see [`ConfigBuilder::merge`].
+    File,
+    /// Build metadata, see [`ConfigBuilder::from_build_info`].
+    BuildInfo,
+    /// The default value.
+    Default,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Builder => write!(f, "the config builder"),
+            Self::Env(name) => write!(f, "env var `{name}`"),
+            Self::File => write!(f, "a config file"),
+            Self::BuildInfo => write!(f, "build info"),
+            Self::Default => write!(f, "the default"),
+        }
+    }
+}
+
+/// Errors that can occur during [`ConfigBuilder::merge_file`].
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConfigFileError {
+    /// The file could not be read.
+    Io(std::io::Error),
+    /// The file is not valid TOML, or has unknown or invalid fields.
+    Toml(toml::de::Error),
+    /// The file is not valid YAML, or has unknown or invalid fields.
+    Yaml(serde_yaml::Error),
+    /// The file extension is not `.toml`, `.yaml` or `.yml`.
+    UnsupportedFormat,
+}
+
+#[cfg(feature = "serde")]
+impl Display for ConfigFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read config file: {err}"),
+            Self::Toml(err) => write!(f, "invalid TOML config file: {err}"),
+            Self::Yaml(err) => write!(f, "invalid YAML config file: {err}"),
+            Self::UnsupportedFormat => write!(f, "unsupported config file format"),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Error for ConfigFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Toml(err) => Some(err),
+            Self::Yaml(err) => Some(err),
+            Self::UnsupportedFormat => None,
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Returns the kinds of issues found when building the config.
+    fn issue_kinds(builder: ConfigBuilder) -> Vec<IssueKind> {
+        builder
+            .build()
+            .err()
+            .map(|err| err.issues().iter().map(|issue| issue.kind).collect())
+            .unwrap_or_default()
+    }
+
     #[test]
     fn builder_validation_empty_service_name() {
         let builder = ConfigBuilder::default().service("");
-        assert!(matches!(
-            builder.build(),
-            Err(BuilderError::InvalidServiceName)
-        ));
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidServiceName]);
     }
 
     #[test]
@@ -236,36 +1688,658 @@ mod tests {
     #[test]
     fn builder_validation_metrics_agent_url_empty() {
         let builder = ConfigBuilder::default().metrics_agent_url("");
-        assert!(matches!(
-            builder.build(),
-            Err(BuilderError::InvalidMetricsAgentUrl)
-        ));
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidMetricsAgentUrl]);
     }
 
     #[test]
     fn builder_validation_metrics_agent_url_with_protocol() {
         let builder = ConfigBuilder::default().metrics_agent_url("http://localhost:8125");
-        assert!(matches!(
-            builder.build(),
-            Err(BuilderError::InvalidMetricsAgentUrl)
-        ));
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidMetricsAgentUrl]);
     }
 
     #[test]
     fn builder_validation_metrics_agent_url_no_port() {
         let builder = ConfigBuilder::default().metrics_agent_url("localhost");
-        assert!(matches!(
-            builder.build(),
-            Err(BuilderError::InvalidMetricsAgentUrl)
-        ));
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidMetricsAgentUrl]);
     }
 
     #[test]
     fn builder_validation_metrics_agent_url_invalid_port() {
         let builder = ConfigBuilder::default().metrics_agent_url("localhost:not-a-port");
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidMetricsAgentUrl]);
+    }
+
+    #[test]
+    fn builder_validation_unix_socket_urls() {
+        let builder = ConfigBuilder::default()
+            .metrics_agent_url("unix:///var/run/datadog/dsd.socket")
+            .trace_agent_url(Some("unix:///var/run/datadog/apm.socket"));
+        assert!(builder.build().is_ok());
+
+        let builder = ConfigBuilder::default().metrics_agent_url("unixstream:///tmp/dsd.socket");
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn builder_validation_unix_socket_url_relative_path() {
+        let builder = ConfigBuilder::default().metrics_agent_url("unix://dsd.socket");
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidMetricsAgentUrl]);
+    }
+
+    #[test]
+    fn builder_validation_trace_agent_url_unixgram() {
+        let builder = ConfigBuilder::default().trace_agent_url(Some("unixgram:///tmp/apm.socket"));
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidTraceAgentUrl]);
+    }
+
+    #[test]
+    fn agent_address_parse() {
+        assert_eq!(
+            AgentAddress::parse("localhost:8125"),
+            Ok(AgentAddress::Host("localhost:8125"))
+        );
+        assert_eq!(
+            AgentAddress::parse("unixgram:///tmp/dsd.socket"),
+            Ok(AgentAddress::Unixgram(Path::new("/tmp/dsd.socket")))
+        );
+        assert_eq!(
+            AgentAddress::parse("udp://localhost:8125"),
+            Err("has an unsupported scheme")
+        );
+        assert_eq!(AgentAddress::parse("localhost"), Err("is missing a port"));
+        assert_eq!(
+            AgentAddress::parse("localhost:not-a-port"),
+            Err("has an invalid port")
+        );
+        assert_eq!(
+            AgentAddress::parse("unix://dsd.socket"),
+            Err("has a relative Unix socket path")
+        );
+    }
+
+    /// Returns an environment lookup with the given variables.
+    fn env_with(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    /// Builds a config from the given environment variables only.
+    fn config_from_env(vars: &[(&str, &str)]) -> Config {
+        ConfigBuilder::from_env(env_with(vars)).build().unwrap()
+    }
+
+    #[test]
+    fn trace_agent_url_from_env_precedence() {
+        assert_eq!(config_from_env(&[]).trace_agent_url, None);
+        assert_eq!(
+            config_from_env(&[
+                ("DD_TRACE_AGENT_URL", "http://datadog:8126/"),
+                ("DD_AGENT_HOST", "10.0.0.1"),
+            ])
+            .trace_agent_url,
+            Some(String::from("datadog:8126"))
+        );
+        assert_eq!(
+            config_from_env(&[
+                ("DD_APM_RECEIVER_SOCKET", "/var/run/datadog/apm.socket"),
+                ("DD_AGENT_HOST", "10.0.0.1"),
+            ])
+            .trace_agent_url,
+            Some(String::from("unix:///var/run/datadog/apm.socket"))
+        );
+        assert_eq!(
+            config_from_env(&[("DD_AGENT_HOST", "10.0.0.1")]).trace_agent_url,
+            Some(String::from("10.0.0.1:8126"))
+        );
+        assert_eq!(
+            config_from_env(&[("DD_TRACE_ENABLED", "true")]).trace_agent_url,
+            Some(String::from("localhost:8126"))
+        );
+        assert_eq!(
+            config_from_env(&[
+                ("DD_TRACE_ENABLED", "false"),
+                ("DD_TRACE_AGENT_URL", "datadog:8126"),
+            ])
+            .trace_agent_url,
+            None
+        );
+    }
+
+    #[test]
+    fn metrics_agent_url_from_env_precedence() {
+        assert_eq!(config_from_env(&[]).metrics_agent_url, "localhost:8125");
+        assert_eq!(
+            config_from_env(&[
+                ("DD_DOGSTATSD_URL", "udp://datadog:8125"),
+                ("DD_DOGSTATSD_SOCKET", "/var/run/datadog/dsd.socket"),
+            ])
+            .metrics_agent_url,
+            "datadog:8125"
+        );
+        assert_eq!(
+            config_from_env(&[
+                ("DD_DOGSTATSD_SOCKET", "/var/run/datadog/dsd.socket"),
+                ("DD_AGENT_HOST", "10.0.0.1"),
+            ])
+            .metrics_agent_url,
+            "unix:///var/run/datadog/dsd.socket"
+        );
+        assert_eq!(
+            config_from_env(&[
+                ("DD_AGENT_HOST", "10.0.0.1"),
+                ("DD_DOGSTATSD_PORT", "18125"),
+            ])
+            .metrics_agent_url,
+            "10.0.0.1:18125"
+        );
+        assert_eq!(
+            config_from_env(&[
+                ("DD_AGENT_HOST", "10.0.0.1"),
+                ("DD_DOGSTATSD_HOST", "fd00::1"),
+            ])
+            .metrics_agent_url,
+            "[fd00::1]:8125"
+        );
+    }
+
+    #[test]
+    fn parse_tags_separators() {
+        assert_eq!(
+            parse_tags("team:payments,region:jp  cluster:main,invalid,git.commit.sha:abc:123"),
+            BTreeMap::from([
+                (String::from("team"), String::from("payments")),
+                (String::from("region"), String::from("jp")),
+                (String::from("cluster"), String::from("main")),
+                (String::from("git.commit.sha"), String::from("abc:123")),
+            ])
+        );
+    }
+
+    #[test]
+    fn builder_precedence() {
+        let file = ConfigFile {
+            service: Some(String::from("file-service")),
+            env: Some(String::from("staging")),
+            version: Some(String::from("1.0.0")),
+            trace_agent_url: Some(String::from("datadog:8126")),
+            trace_sample_rate: Some(0.5),
+            tags: BTreeMap::from([
+                (String::from("team"), String::from("file")),
+                (String::from("region"), String::from("jp")),
+            ]),
+            log_filter: Some(String::from("debug")),
+            ..ConfigFile::default()
+        };
+        let config = ConfigBuilder::from_env(env_with(&[
+            ("DD_ENV", "production"),
+            ("DD_VERSION", "2.0.0"),
+            ("DD_TAGS", "team:env"),
+            ("DD_TRACE_SAMPLE_RATE", "0.25"),
+        ]))
+        .from_build_info(BuildInfo::new("payments", "0.1.0", None, None))
+        .merge(file)
+        .version("3.0.0")
+        .build()
+        .unwrap();
+
+        assert_eq!(config.service, "file-service");
+        assert_eq!(config.env, "production");
+        assert_eq!(config.version, "3.0.0");
+        assert_eq!(config.trace_agent_url.as_deref(), Some("datadog:8126"));
+        assert_eq!(config.trace_sample_rate, 0.25);
+        assert_eq!(config.log_filter, "debug");
+        assert_eq!(
+            config.tags,
+            vec![
+                (String::from("region"), String::from("jp")),
+                (String::from("team"), String::from("env")),
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_trace_agent_url_none_overrides_env() {
+        let config = ConfigBuilder::from_env(env_with(&[("DD_AGENT_HOST", "datadog")]))
+            .trace_agent_url(None::<String>)
+            .build()
+            .unwrap();
+        assert_eq!(config.trace_agent_url, None);
+    }
+
+    #[test]
+    fn builder_later_merges_take_precedence() {
+        let config = ConfigBuilder::from_env(env_with(&[]))
+            .merge(ConfigFile {
+                service: Some(String::from("payments")),
+                env: Some(String::from("staging")),
+                ..ConfigFile::default()
+            })
+            .merge(ConfigFile {
+                env: Some(String::from("production")),
+                ..ConfigFile::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.service, "payments");
+        assert_eq!(config.env, "production");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn builder_merge_file() {
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("komoju-dd-{}.toml", std::process::id()));
+        let yaml_path = dir.join(format!("komoju-dd-{}.yaml", std::process::id()));
+        std::fs::write(
+            &toml_path,
+            "service = \"payments\"\nlog_filter = \"warn\"\n\n[tags]\nteam = \"payments\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &yaml_path,
+            "env: production\ntrace_enabled: true\ntrace_sample_rate: 0.5\nmetrics_aggregation_interval: 10\n",
+        )
+        .unwrap();
+
+        let config = ConfigBuilder::from_env(env_with(&[]))
+            .merge_file(&toml_path)
+            .unwrap()
+            .merge_file(&yaml_path)
+            .unwrap()
+            .build()
+            .unwrap();
+        let _ = std::fs::remove_file(&toml_path);
+        let _ = std::fs::remove_file(&yaml_path);
+
+        assert_eq!(config.service, "payments");
+        assert_eq!(config.env, "production");
+        assert_eq!(config.log_filter, "warn");
+        assert_eq!(config.trace_agent_url.as_deref(), Some("localhost:8126"));
+        assert_eq!(config.trace_sample_rate, 0.5);
+        assert_eq!(config.metrics_aggregation_interval, Duration::from_secs(10));
+        assert_eq!(
+            config.tags,
+            vec![(String::from("team"), String::from("payments"))]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn builder_merge_file_errors() {
+        let path = std::env::temp_dir().join(format!("komoju-dd-{}.yml", std::process::id()));
+        std::fs::write(&path, "sevrice: payments\n").unwrap();
+        let result = ConfigBuilder::from_env(env_with(&[])).merge_file(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(result, Err(ConfigFileError::Yaml(_))));
+
         assert!(matches!(
-            builder.build(),
-            Err(BuilderError::InvalidMetricsAgentUrl)
+            ConfigBuilder::from_env(env_with(&[])).merge_file("datadog.json"),
+            Err(ConfigFileError::UnsupportedFormat)
         ));
+        assert!(matches!(
+            ConfigBuilder::from_env(env_with(&[])).merge_file("missing.toml"),
+            Err(ConfigFileError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn builder_tag_replaces_same_key() {
+        let config = ConfigBuilder::default()
+            .tag("team", "payments")
+            .tag("team", "platform")
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.tags,
+            vec![(String::from("team"), String::from("platform"))]
+        );
+    }
+
+    #[test]
+    fn builder_validation_empty_tag_key() {
+        let builder = ConfigBuilder::default().tag("", "payments");
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidTag]);
+    }
+
+    #[test]
+    fn builder_validation_env_and_version() {
+        let builder = ConfigBuilder::from_env(env_with(&[])).env("Production");
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidEnv]);
+
+        let builder = ConfigBuilder::from_env(env_with(&[])).env("prod-jp/main");
+        assert_eq!(issue_kinds(builder), []);
+
+        let builder = ConfigBuilder::from_env(env_with(&[])).version("1.2.3 beta");
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidVersion]);
+
+        let builder = ConfigBuilder::from_env(env_with(&[])).tag("team", "pay|ments");
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidTag]);
+    }
+
+    #[test]
+    fn builder_validation_reports_all_issues_with_sources() {
+        let err = ConfigBuilder::from_env(env_with(&[
+            ("DD_ENV", "Production"),
+            ("DD_AGENT_HOST", "datadog"),
+            ("DD_DOGSTATSD_PORT", "dogstatsd"),
+        ]))
+        .service("")
+        .merge(ConfigFile {
+            trace_agent_url: Some(String::from("unixgram:///var/run/datadog/apm.socket")),
+            ..ConfigFile::default()
+        })
+        .trace_agent_url(Some("http://datadog:8126"))
+        .build()
+        .unwrap_err();
+
+        let issues: Vec<_> = err
+            .issues()
+            .iter()
+            .map(|issue| (issue.kind, issue.field.as_str(), issue.source, issue.reason))
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                (
+                    IssueKind::InvalidServiceName,
+                    "service",
+                    ConfigSource::Builder,
+                    "is empty"
+                ),
+                (
+                    IssueKind::InvalidEnv,
+                    "env",
+                    ConfigSource::Env("DD_ENV"),
+                    "contains characters other than lowercase letters, digits, `_`, `-`, `.`, `:` and `/`"
+                ),
+                (
+                    IssueKind::InvalidMetricsAgentUrl,
+                    "metrics_agent_url",
+                    ConfigSource::Env("DD_DOGSTATSD_PORT"),
+                    "has an invalid port"
+                ),
+                (
+                    IssueKind::InvalidTraceAgentUrl,
+                    "trace_agent_url",
+                    ConfigSource::Builder,
+                    "has an unsupported scheme"
+                ),
+            ]
+        );
+        assert_eq!(
+            err.issues()[2].to_string(),
+            "invalid metrics agent URL: `datadog:dogstatsd` from env var `DD_DOGSTATSD_PORT` has \
+             an invalid port; use `host:port`, or `unix:///path/to/dsd.socket` for a Unix socket"
+        );
+    }
+
+    #[test]
+    fn builder_validation_sources_of_tags() {
+        let err = ConfigBuilder::from_env(env_with(&[("DD_TAGS", "team:pay#ments")]))
+            .metrics_agent_url("localhost")
+            .version("")
+            .build()
+            .unwrap_err();
+        let sources: Vec<_> = err
+            .issues()
+            .iter()
+            .map(|issue| (issue.field.as_str(), issue.source))
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                ("version", ConfigSource::Builder),
+                ("tags.team", ConfigSource::Env("DD_TAGS")),
+                ("metrics_agent_url", ConfigSource::Builder),
+            ]
+        );
+    }
+
+    #[test]
+    fn report_sources() {
+        let config = ConfigBuilder::from_env(env_with(&[
+            ("DD_ENV", "production"),
+            ("DD_TRACE_ENABLED", "false"),
+            ("DD_AGENT_HOST", "datadog"),
+        ]))
+        .service("payments")
+        .tag("team", "payments")
+        .build()
+        .unwrap();
+        let report = config.report();
+        let setting = |name: &str| {
+            report
+                .settings
+                .iter()
+                .find(|setting| setting.name == name)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            setting("service"),
+            "service=payments from the config builder"
+        );
+        assert_eq!(setting("env"), "env=production from env var `DD_ENV`");
+        assert_eq!(setting("version"), "version=unknown from the default");
+        assert_eq!(
+            setting("trace_enabled"),
+            "trace_enabled=false from env var `DD_TRACE_ENABLED`"
+        );
+        assert_eq!(
+            setting("trace_agent_url"),
+            "trace_agent_url unset from the default"
+        );
+        assert_eq!(
+            setting("metrics_agent_url"),
+            "metrics_agent_url=datadog:8125 from env var `DD_AGENT_HOST`"
+        );
+        assert_eq!(
+            setting("tags.team"),
+            "tags.team=payments from the config builder"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn report_serializes_to_json() {
+        let config = ConfigBuilder::from_env(env_with(&[("DD_SERVICE", "payments")]))
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(config.report()).unwrap();
+        assert_eq!(
+            json["settings"][0],
+            serde_json::json!({
+                "name": "service",
+                "value": "payments",
+                "source": { "type": "env", "name": "DD_SERVICE" },
+            })
+        );
+        assert_eq!(
+            json["settings"][1]["source"],
+            serde_json::json!({ "type": "default" })
+        );
+    }
+
+    #[test]
+    fn builder_agentless_settings() {
+        let config = ConfigBuilder::from_env(env_with(&[
+            ("DD_API_KEY", "secret"),
+            ("DD_SITE", "datadoghq.eu"),
+        ]))
+        .build()
+        .unwrap();
+        assert_eq!(config.api_key.as_ref().map(ApiKey::expose), Some("secret"));
+        assert_eq!(config.site, "datadoghq.eu");
+        assert!(!format!("{config:?}").contains("secret"));
+        assert!(!config.report().to_string().contains("secret"));
+
+        let builder = ConfigBuilder::from_env(env_with(&[]))
+            .site("https://datadoghq.com")
+            .api_key("");
+        assert_eq!(
+            issue_kinds(builder),
+            [IssueKind::InvalidSite, IssueKind::InvalidApiKey]
+        );
+    }
+
+    #[test]
+    fn builder_signal_switches() {
+        let config = ConfigBuilder::from_env(env_with(&[])).build().unwrap();
+        assert!(!config.logs_injection);
+        assert!(config.metrics_enabled);
+        assert!(config.pretty_logs);
+
+        let config = ConfigBuilder::from_env(env_with(&[
+            ("DD_ENV", "production"),
+            ("DD_METRICS_ENABLED", "false"),
+            ("DD_LOGS_PRETTY", "true"),
+        ]))
+        .build()
+        .unwrap();
+        assert!(config.logs_injection);
+        assert!(!config.metrics_enabled);
+        assert!(config.pretty_logs);
+
+        let config = ConfigBuilder::from_env(env_with(&[("DD_AGENT_HOST", "datadog")]))
+            .trace_enabled(false)
+            .logs_injection(false)
+            .build()
+            .unwrap();
+        assert_eq!(config.trace_agent_url, None);
+        assert!(!config.logs_injection);
+    }
+
+    #[test]
+    fn builder_profiles() {
+        let profile = |vars: &[(&str, &str)]| config_from_env(vars).profile;
+        assert_eq!(profile(&[]), Profile::Development);
+        assert_eq!(profile(&[("DD_ENV", "local")]), Profile::Development);
+        assert_eq!(profile(&[("DD_ENV", "test")]), Profile::Test);
+        assert_eq!(profile(&[("DD_ENV", "staging")]), Profile::Production);
+
+        let config = ConfigBuilder::from_env(env_with(&[("DD_ENV", "sandbox")]))
+            .merge(ConfigFile {
+                profiles: BTreeMap::from([(String::from("sandbox"), Profile::Test)]),
+                ..ConfigFile::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.profile, Profile::Test);
+        assert!(!config.metrics_enabled);
+        assert!(!config.pretty_logs);
+        assert!(!config.logs_injection);
+
+        let config = ConfigBuilder::from_env(env_with(&[("DD_ENV", "local")]))
+            .profile(Profile::Production)
+            .build()
+            .unwrap();
+        assert!(config.logs_injection);
+        assert!(!config.pretty_logs);
+    }
+
+    #[test]
+    fn report_profile_source() {
+        let report = config_from_env(&[("DD_ENV", "local")]).report();
+        let profile = &report.settings[2];
+        assert_eq!(profile.name, "profile");
+        assert_eq!(profile.value.as_deref(), Some("development"));
+        assert_eq!(profile.source, ConfigSource::Env("DD_ENV"));
+    }
+
+    #[test]
+    fn builder_metrics_cardinality_limit() {
+        assert_eq!(config_from_env(&[]).metrics_cardinality_limit, 0);
+        assert_eq!(
+            config_from_env(&[("DD_METRICS_CARDINALITY_LIMIT", "50")]).metrics_cardinality_limit,
+            50
+        );
+
+        let builder =
+            ConfigBuilder::from_env(env_with(&[("DD_METRICS_CARDINALITY_LIMIT", "lots")]));
+        let error = builder.build().unwrap_err();
+        let issue = &error.issues()[0];
+        assert_eq!(issue.kind, IssueKind::InvalidCardinalityLimit);
+        assert_eq!(
+            issue.source,
+            ConfigSource::Env("DD_METRICS_CARDINALITY_LIMIT")
+        );
+
+        let config = ConfigBuilder::from_env(env_with(&[("DD_METRICS_CARDINALITY_LIMIT", "lots")]))
+            .metrics_cardinality_limit(0)
+            .build()
+            .unwrap();
+        assert_eq!(config.metrics_cardinality_limit, 0);
+    }
+
+    #[test]
+    fn builder_metrics_aggregation_interval() {
+        assert_eq!(
+            config_from_env(&[]).metrics_aggregation_interval,
+            Duration::ZERO
+        );
+        assert_eq!(
+            config_from_env(&[("DD_METRICS_AGGREGATION_INTERVAL", "10")])
+                .metrics_aggregation_interval,
+            Duration::from_secs(10)
+        );
+
+        let error =
+            ConfigBuilder::from_env(env_with(&[("DD_METRICS_AGGREGATION_INTERVAL", "10s")]))
+                .build()
+                .unwrap_err();
+        assert_eq!(
+            error.issues()[0].kind,
+            IssueKind::InvalidAggregationInterval
+        );
+    }
+
+    #[test]
+    fn builder_trace_sample_rate() {
+        assert_eq!(config_from_env(&[]).trace_sample_rate, 1.0);
+        assert_eq!(
+            config_from_env(&[("DD_TRACE_SAMPLE_RATE", "0.1")]).trace_sample_rate,
+            0.1
+        );
+
+        let error = ConfigBuilder::from_env(env_with(&[("DD_TRACE_SAMPLE_RATE", "half")]))
+            .build()
+            .unwrap_err();
+        let issue = &error.issues()[0];
+        assert_eq!(issue.kind, IssueKind::InvalidSampleRate);
+        assert_eq!(issue.source, ConfigSource::Env("DD_TRACE_SAMPLE_RATE"));
+
+        let config = ConfigBuilder::from_env(env_with(&[("DD_TRACE_SAMPLE_RATE", "half")]))
+            .trace_sample_rate(0.5)
+            .build()
+            .unwrap();
+        assert_eq!(config.trace_sample_rate, 0.5);
+
+        let error = ConfigBuilder::from_env(env_with(&[("DD_TRACE_SAMPLE_RATE", "10")]))
+            .build()
+            .unwrap_err();
+        let issue = &error.issues()[0];
+        assert_eq!(issue.kind, IssueKind::InvalidSampleRate);
+        assert_eq!(issue.source, ConfigSource::Env("DD_TRACE_SAMPLE_RATE"));
+        assert_eq!(issue.reason, "is not between `0.0` and `1.0`");
+
+        let builder = ConfigBuilder::from_env(env_with(&[])).trace_sample_rate(-0.1);
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidSampleRate]);
+        let builder = ConfigBuilder::from_env(env_with(&[])).trace_sample_rate(f64::NAN);
+        assert_eq!(issue_kinds(builder), [IssueKind::InvalidSampleRate]);
+    }
+
+    #[test]
+    fn builder_from_build_info() {
+        let build_info = BuildInfo::new("payments", "1.2.3", Some("abc123"), Some(""));
+        let config = ConfigBuilder::default()
+            .from_build_info(build_info)
+            .build()
+            .unwrap();
+        assert_eq!(config.git_repository_url, None);
+        assert_eq!(config.git_commit_sha.as_deref(), Some("abc123"));
     }
 }
@@ -9,6 +9,19 @@ use tracing_datadog::{
     http::W3CTraceContextHeaders,
 };
 
+#[cfg(feature = "tower")]
+mod server;
+
+#[cfg(any(feature = "axum_ws", feature = "tonic"))]
+pub(crate) use server::touch_span;
+#[cfg(feature = "axum")]
+pub(crate) use server::{Framework, RejectionInfo};
+#[cfg(feature = "tower")]
+pub use server::{
+    HttpTraceLayer, HttpTraceService, LoadRejection, RequestSpan, ResourceNamer, ResponseFuture,
+    TracedBody,
+};
+
 /// Returns a Datadog-style path group from a request path, with dynamic segments replaced by '?'.
 ///
 /// # Examples
@@ -38,6 +51,86 @@ pub fn path_group(path: &str) -> String {
 static STATIC_SEGMENT_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new("^(?:[^0-9]*|v[0-9]+)$").expect("invalid static segment regex"));
 
+/// Framework-independent information about a server request, for [`make_server_span`].
+#[cfg(any(feature = "actix", feature = "tower"))]
+pub(crate) struct ServerRequest<'a> {
+    /// The operation name of the span.
+    pub(crate) operation: &'static str,
+    pub(crate) method: &'a str,
+    pub(crate) path: &'a str,
+    /// The `Host` header.
+    pub(crate) host: Option<&'a str>,
+    /// The host of the request URI, if absolute.
+    pub(crate) uri_host: Option<&'a str>,
+    pub(crate) scheme: Option<&'a str>,
+    pub(crate) user_agent: Option<&'a str>,
+    /// The `X-Forwarded-For` header, or the address of the connected client.
+    pub(crate) client_ip: Option<String>,
+    pub(crate) request_id: Option<&'a str>,
+    pub(crate) content_length: Option<u64>,
+    /// The HTTP version, e.g. `"1.1"`.
+    pub(crate) protocol_version: &'static str,
+}
+
+/// Creates a span for a server request, with the same fields regardless of the web framework.
+///
+/// The resource defaults to the [`path_group`] of the path, and should be updated along with
+/// `http.route` once the route is known.
+#[cfg(any(feature = "actix", feature = "tower"))]
+pub(crate) fn make_server_span(req: &ServerRequest<'_>) -> tracing::Span {
+    use tracing::field::Empty;
+
+    tracing::info_span!(
+        "HTTP request",
+        operation = req.operation,
+        resource = format!("{} {}", req.method, path_group(req.path)),
+        http.base_url = req.host.or(req.uri_host),
+        http.method = req.method,
+        http.url = req.path,
+        http.useragent = req.user_agent,
+        http.route = Empty,
+        http.client.ip = req.client_ip.as_deref(),
+        http.request_id = req.request_id,
+        http.status_code = Empty,
+        http.request.content_length = req.content_length,
+        http.response.content_length = Empty,
+        http.response.time_to_first_byte_ms = Empty,
+        http.response.body.size = Empty,
+        http.response.bytes_sent = Empty,
+        http.client_disconnected = Empty,
+        http.cancelled = Empty,
+        http.timeout = Empty,
+        http.shed = Empty,
+        http.rejection.type = Empty,
+        http.rejection.message = Empty,
+        network.protocol.version = req.protocol_version,
+        server.address = req.uri_host,
+        url.scheme = req.scheme,
+        request_id = Empty,
+        error.type = Empty,
+        error.message = Empty,
+        error.stack = Empty,
+        span.kind = "server",
+        span.type = "web",
+
+        // Our internal authentication claims
+        auth.method = Empty,
+        auth.user_uuid = Empty,
+        auth.merchant_uuid = Empty,
+        auth.account_uuid = Empty,
+        auth.role = Empty,
+        auth.api_version = Empty,
+
+        // Datadog AppSec identity tags
+        usr.id = Empty,
+        usr.email = Empty,
+        usr.session_id = Empty,
+        usr.role = Empty,
+        usr.merchant = Empty,
+        usr.account = Empty,
+    )
+}
+
 /// Attaches tracing headers to a request's [`HeaderMap`], so that the far side can continue the
 /// current trace.
 ///
@@ -0,0 +1,1258 @@
+//! Framework-agnostic tracing for HTTP servers built on `tower`.
+
+use crate::statsd::StatsD;
+use bytes::Buf;
+use http::{Extensions, Method, Request, Response, header, request::Parts};
+use http_body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Debug, Formatter},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{Layer, Service, load_shed::error::Overloaded, timeout::error::Elapsed};
+use tracing::Span;
+use tracing_datadog::{
+    context::{TraceContextExt, TracingContextExt},
+    http::W3CTraceContextHeaders,
+};
+
+/// Framework-specific request information for [`HttpTraceLayer`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Framework {
+    /// The operation name of request spans.
+    pub(crate) operation: &'static str,
+    /// Returns the route that matched a request, if known.
+    pub(crate) route: fn(&Extensions) -> Option<&str>,
+    /// Returns the address of the connected client, if known.
+    pub(crate) peer_addr: fn(&Extensions) -> Option<SocketAddr>,
+}
+
+impl Framework {
+    /// Plain `tower` services, which know neither routes nor connection info.
+    const TOWER: Self = Self {
+        operation: "http.request",
+        route: |_| None,
+        peer_addr: |extensions| extensions.get::<SocketAddr>().copied(),
+    };
+}
+
+/// Creates a span from a request.
+fn make_span_from_request<B>(req: &Request<B>, framework: &Framework) -> Span {
+    let header = |name: header::HeaderName| req.headers().get(name).and_then(|h| h.to_str().ok());
+    super::make_server_span(&super::ServerRequest {
+        operation: framework.operation,
+        method: req.method().as_str(),
+        path: req.uri().path(),
+        host: header(header::HOST),
+        uri_host: req.uri().host(),
+        scheme: req.uri().scheme_str(),
+        user_agent: header(header::USER_AGENT),
+        client_ip: header(header::HeaderName::from_static("x-forwarded-for"))
+            .map(str::to_string)
+            .or_else(|| (framework.peer_addr)(req.extensions()).map(|addr| addr.ip().to_string())),
+        request_id: header(header::HeaderName::from_static("x-request-id")),
+        content_length: header(header::CONTENT_LENGTH).and_then(|h| h.parse().ok()),
+        protocol_version: match req.version() {
+            http::Version::HTTP_10 => "1.0",
+            http::Version::HTTP_11 => "1.1",
+            http::Version::HTTP_2 => "2.0",
+            http::Version::HTTP_3 => "3.0",
+            _ => "",
+        },
+    })
+}
+
+/// Updates a span with tags from the response.
+fn update_span_from_response<B>(span: &Span, response: &Response<B>)
+where
+    B: Body,
+{
+    span.record("http.status_code", response.status().as_u16());
+    if let Some(content_length) = response_content_length(response) {
+        span.record("http.response.content_length", content_length);
+    }
+}
+
+/// Returns the length of a response body, if known up front.
+fn response_content_length<B>(response: &Response<B>) -> Option<u64>
+where
+    B: Body,
+{
+    response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok()?.parse().ok())
+        .or_else(|| response.body().size_hint().exact())
+}
+
+/// Updates a span with tags from an error response.
+fn update_span_from_error<E>(span: &Span, error: &E)
+where
+    E: Error,
+{
+    crate::tracing::record_error(span, error);
+}
+
+/// Updates a span with tags from a response or error.
+fn update_span_from_response_or_error<B, E>(span: &Span, response: &Result<Response<B>, E>)
+where
+    B: Body,
+    E: Error,
+{
+    match response {
+        Ok(response) => update_span_from_response(span, response),
+        Err(err) => update_span_from_error(span, err),
+    }
+}
+
+/// Tower layer to create spans for HTTP server requests, independent of the web framework.
+///
+/// This works with any service handling [`http::Request`]s, like plain `hyper` servers or
+/// `tower`-based proxies. As the route is not known, the resource and metrics use the
+/// [`path_group`](super::path_group) of the request path instead. The client IP is taken from
+/// the `X-Forwarded-For` header, or from a [`SocketAddr`] request extension if present.
+///
+/// Axum applications should use
+#[cfg_attr(
+    feature = "axum",
+    doc = "[`AxumTraceLayer`](struct@crate::axum::AxumTraceLayer)"
+)]
+#[cfg_attr(not(feature = "axum"), doc = "`axum::AxumTraceLayer`")]
+/// instead, which also records the matched route.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::http::HttpTraceLayer;
+/// use std::convert::Infallible;
+/// use tower::{ServiceBuilder, service_fn};
+///
+/// let service = ServiceBuilder::new()
+///     .layer(HttpTraceLayer::new())
+///     .service(service_fn(|_req: http::Request<String>| async {
+///         Ok::<_, Infallible>(http::Response::new(String::from("ok")))
+///     }));
+/// ```
+#[derive(Clone)]
+pub struct HttpTraceLayer {
+    /// Whether to keep the span open until the response body has been sent.
+    trace_response_body: bool,
+    /// Status code to record for requests dropped before completion, if any.
+    cancelled_status_code: Option<u16>,
+    /// Request metrics to emit, if enabled.
+    metrics: Option<ServerMetrics>,
+    /// Custom resource naming, if any.
+    resource_namer: Option<Arc<dyn ResourceNamer>>,
+    /// Framework-specific request information.
+    framework: Framework,
+}
+
+impl Debug for HttpTraceLayer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpTraceLayer")
+            .field("trace_response_body", &self.trace_response_body)
+            .field("cancelled_status_code", &self.cancelled_status_code)
+            .field("metrics", &self.metrics.is_some())
+            .field("resource_namer", &self.resource_namer.is_some())
+            .field("operation", &self.framework.operation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HttpTraceLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpTraceLayer {
+    /// Creates a new layer with the default settings.
+    pub const fn new() -> Self {
+        Self {
+            trace_response_body: false,
+            cancelled_status_code: None,
+            metrics: None,
+            resource_namer: None,
+            framework: Framework::TOWER,
+        }
+    }
+
+    /// Keeps the span open until the response body has been fully sent, rather than closing it as
+    /// soon as the response headers are ready.
+    ///
+    /// This is useful for streaming responses like SSE or large downloads, which would otherwise
+    /// report near-zero durations. The span additionally records `http.response.body.size`,
+    /// `http.response.bytes_sent` and `http.client_disconnected`.
+    ///
+    /// Disabled by default.
+    pub fn trace_response_body(mut self, enabled: bool) -> Self {
+        self.trace_response_body = enabled;
+        self
+    }
+
+    /// Sets a pseudo status code, like nginx's `499` "client closed request", recorded as
+    /// `http.status_code` for requests dropped before a response was produced.
+    ///
+    /// Dropped requests are always tagged with `http.cancelled = true`, and counted in the
+    /// `http.server.request.cancelled` metric if [metrics](Self::metrics) are enabled. The layer
+    /// cannot tell why a request was dropped though: besides the client closing the connection, it
+    /// may be a timeout, load shedding or any other middleware wrapping this layer, or a server
+    /// shutting down. Only set this if the layer is
+    /// the outermost one, so that dropped requests are most likely closed connections.
+    ///
+    /// Unset by default, so that dropped requests have no status code.
+    pub fn cancelled_status_code(mut self, status_code: u16) -> Self {
+        self.cancelled_status_code = Some(status_code);
+        self
+    }
+
+    /// Emits HTTP server metrics for every request to `statsd`:
+    ///
+    /// - `http.server.request.count`, a counter
+    /// - `http.server.request.duration`, a distribution in milliseconds
+    /// - `http.server.requests.in_flight`, a gauge
+    ///
+    /// All metrics are tagged with `route` and `method`, and the first two additionally with
+    /// `status_class` and `status_code`. Instead of a status, requests dropped before a response
+    /// was produced are tagged with `cancelled:true`, see
+    /// [`cancelled_status_code`](Self::cancelled_status_code), and requests for which the service
+    /// returned an error with `error:true`. The route is the one matched by the web framework, if
+    /// known, or the [`path_group`](super::path_group) of the path otherwise, to keep the
+    /// cardinality bounded.
+    ///
+    /// Disabled by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::{Config, http::HttpTraceLayer, statsd::StatsD};
+    ///
+    /// let config = Config::builder().build().expect("invalid config");
+    /// let layer = HttpTraceLayer::new().metrics(StatsD::init_global(&config));
+    /// ```
+    pub fn metrics(mut self, statsd: &'static StatsD) -> Self {
+        self.metrics = Some(ServerMetrics {
+            statsd,
+            in_flight: Default::default(),
+        });
+        self
+    }
+
+    /// Sets a custom [`ResourceNamer`] to compute span resources from requests.
+    ///
+    /// By default, the resource is `"{method} {route}"`, which is not very useful for endpoints
+    /// like GraphQL or JSON-RPC that serve everything from a single route. Handlers can also
+    /// rename the resource later on through [`RequestSpan::set_resource`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::http::HttpTraceLayer;
+    ///
+    /// let layer = HttpTraceLayer::new().resource_namer(|parts: &http::request::Parts| {
+    ///     let method = parts.headers.get("X-RPC-Method")?.to_str().ok()?;
+    ///     Some(format!("RPC {method}"))
+    /// });
+    /// ```
+    pub fn resource_namer(mut self, resource_namer: impl ResourceNamer) -> Self {
+        self.resource_namer = Some(Arc::new(resource_namer));
+        self
+    }
+
+    /// Sets the framework-specific request information.
+    #[cfg(feature = "axum")]
+    pub(crate) const fn framework(mut self, framework: Framework) -> Self {
+        self.framework = framework;
+        self
+    }
+}
+
+/// Computes span resources from requests, for [`HttpTraceLayer::resource_namer`].
+///
+/// This is implemented for closures taking request [`Parts`].
+pub trait ResourceNamer: Send + Sync + 'static {
+    /// Returns the resource for a request, or `None` to fall back to the default resource.
+    fn resource(&self, parts: &Parts) -> Option<String>;
+}
+
+impl<F> ResourceNamer for F
+where
+    F: Fn(&Parts) -> Option<String> + Send + Sync + 'static,
+{
+    fn resource(&self, parts: &Parts) -> Option<String> {
+        self(parts)
+    }
+}
+
+/// Marks a request as rejected to protect the service, rather than failed.
+///
+/// [`HttpTraceLayer`] recognizes these from errors produced by tower's
+/// [`Timeout`](tower::timeout::Timeout) and [`LoadShed`](tower::load_shed::LoadShed) middleware,
+/// and from response extensions for responses produced by e.g. `HandleErrorLayer` or custom
+/// rate limiting. Rejected requests are tagged with `http.timeout = true` or `http.shed = true`,
+/// and counted in the `http.server.request.timeout` and `http.server.request.shed` metrics if
+/// [metrics](HttpTraceLayer::metrics) are enabled.
+///
+/// With the `axum` feature, these also convert into `408 Request Timeout` and
+/// `503 Service Unavailable` responses, e.g. for use in Axum's `HandleErrorLayer`.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::http::LoadRejection;
+/// use tower::{BoxError, timeout::error::Elapsed};
+///
+/// let err: BoxError = Box::new(Elapsed::new());
+/// assert_eq!(LoadRejection::from_error(&*err), Some(LoadRejection::Timeout));
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LoadRejection {
+    /// The request took too long to handle.
+    Timeout,
+    /// The request was shed because the service is overloaded.
+    Shed,
+}
+
+impl LoadRejection {
+    /// Classifies an error from tower's `Timeout` or `LoadShed` middleware, including errors
+    /// wrapping them as their source.
+    pub fn from_error(error: &(dyn Error + 'static)) -> Option<Self> {
+        let mut error = Some(error);
+        while let Some(err) = error {
+            if err.is::<Elapsed>() {
+                return Some(Self::Timeout);
+            }
+            if err.is::<Overloaded>() {
+                return Some(Self::Shed);
+            }
+            error = err.source();
+        }
+        None
+    }
+
+    /// Returns the span tag and metric name suffix for this rejection.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Shed => "shed",
+        }
+    }
+}
+
+/// Response extension describing a traced extractor rejection.
+#[derive(Clone, Debug)]
+pub(crate) struct RejectionInfo {
+    pub(crate) rejection_type: &'static str,
+    pub(crate) message: String,
+}
+
+/// The span of the current request, as created by [`HttpTraceLayer`].
+///
+/// This is stored in the request extensions, and allows updating the request span even from
+/// within nested spans. With the `axum` feature, it is also available as an extractor in
+/// handlers. If the request was not traced, the extractor wraps a disabled span, so all
+/// operations are no-ops.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::http::RequestSpan;
+///
+/// fn graphql(req: &http::Request<String>) {
+///     // Parse the operation name from the body.
+///     let operation = "getMerchant";
+///     if let Some(span) = req.extensions().get::<RequestSpan>() {
+///         span.set_resource(format!("POST /graphql {operation}"));
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RequestSpan {
+    pub(crate) span: Span,
+    /// The matched route, or path group if no route matched.
+    pub(crate) route: String,
+}
+
+impl RequestSpan {
+    /// Sets the resource of the request span.
+    pub fn set_resource(&self, resource: impl AsRef<str>) {
+        self.span.record("resource", resource.as_ref());
+    }
+
+    /// Returns the request span.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Returns the matched route, or the path group if no route matched.
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+impl<S> Layer<S> for HttpTraceLayer {
+    /// The wrapped service
+    type Service = HttpTraceService<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpTraceService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Middleware `Service` that creates spans for every request, see [`HttpTraceLayer`].
+#[derive(Debug, Clone)]
+pub struct HttpTraceService<S> {
+    /// The inner service layer.
+    inner: S,
+    /// The settings of the layer that created this service.
+    layer: HttpTraceLayer,
+}
+
+impl<S, B, B2> Service<Request<B>> for HttpTraceService<S>
+where
+    S: Service<Request<B>, Response = Response<B2>> + Clone + Send + 'static,
+    S::Error: Error + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+    B2: Body,
+{
+    type Response = Response<TracedBody<B2>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut req = req;
+        let span = {
+            let span = make_span_from_request(&req, &self.layer.framework);
+
+            let method = req.method().as_str();
+            if let Some(route) = (self.layer.framework.route)(req.extensions()) {
+                span.record("resource", format!("{method} {route}").trim());
+                span.record("http.route", route);
+            }
+
+            if let Some(resource_namer) = &self.layer.resource_namer {
+                let (parts, body) = req.into_parts();
+                if let Some(resource) = resource_namer.resource(&parts) {
+                    span.record("resource", resource);
+                }
+                req = Request::from_parts(parts, body);
+            }
+
+            span.set_context(
+                req.headers()
+                    .extract_trace_context::<W3CTraceContextHeaders>(),
+            );
+
+            span
+        };
+        let method = req.method().clone();
+        let route = match (self.layer.framework.route)(req.extensions()) {
+            Some(route) => route.to_string(),
+            None => super::path_group(req.uri().path()),
+        };
+        req.extensions_mut().insert(RequestSpan {
+            span: span.clone(),
+            route: route.clone(),
+        });
+        let metrics = self
+            .layer
+            .metrics
+            .as_ref()
+            .map(|metrics| RequestMetrics::start(metrics, &method, &route));
+        let future = {
+            let _ = span.enter();
+            self.inner.call(req)
+        };
+        ResponseFuture {
+            inner: future,
+            span,
+            layer: self.layer.clone(),
+            method,
+            route,
+            start: Instant::now(),
+            metrics,
+            completed: false,
+        }
+    }
+}
+
+pin_project! {
+    /// Response future of [`HttpTraceService`].
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+        layer: HttpTraceLayer,
+        method: Method,
+        route: String,
+        start: Instant,
+        metrics: Option<RequestMetrics>,
+        completed: bool,
+    }
+
+    impl<F> PinnedDrop for ResponseFuture<F> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if !*this.completed {
+                record_cancellation(this.span, this.layer, this.method, this.route);
+                if let Some(metrics) = this.metrics.take() {
+                    metrics.finish(match this.layer.cancelled_status_code {
+                        Some(status_code) => Outcome::Status(status_code),
+                        None => Outcome::Cancelled,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Request metrics of an [`HttpTraceLayer`].
+#[derive(Clone)]
+struct ServerMetrics {
+    statsd: &'static StatsD,
+    /// Number of requests currently being handled, by metric tags.
+    in_flight: Arc<Mutex<HashMap<[String; 2], i64>>>,
+}
+
+/// Metrics for a request that is being handled.
+struct RequestMetrics {
+    statsd: &'static StatsD,
+    in_flight: Arc<Mutex<HashMap<[String; 2], i64>>>,
+    /// The `method` and `route` tags.
+    tags: [String; 2],
+    start: Instant,
+}
+
+impl RequestMetrics {
+    /// Starts tracking a request.
+    fn start(metrics: &ServerMetrics, method: &Method, route: &str) -> Self {
+        let metrics = Self {
+            statsd: metrics.statsd,
+            in_flight: metrics.in_flight.clone(),
+            tags: [format!("method:{method}"), format!("route:{route}")],
+            start: Instant::now(),
+        };
+        metrics.update_in_flight(1);
+        metrics
+    }
+
+    /// Finishes tracking a request.
+    fn finish(self, outcome: Outcome) {
+        let duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        let mut tags = self.tags.to_vec();
+        match outcome {
+            Outcome::Status(status_code) => {
+                tags.push(format!("status_class:{}xx", status_code / 100));
+                tags.push(format!("status_code:{status_code}"));
+            }
+            Outcome::Cancelled => tags.push(String::from("cancelled:true")),
+            Outcome::Error => tags.push(String::from("error:true")),
+        }
+
+        let _ = self.statsd.incr("http.server.request.count", &tags);
+        let _ = self.statsd.distribution(
+            "http.server.request.duration",
+            duration_ms.to_string(),
+            &tags,
+        );
+        self.update_in_flight(-1);
+    }
+
+    /// Adjusts the number of in-flight requests and reports the new value.
+    fn update_in_flight(&self, delta: i64) {
+        let count = {
+            let mut in_flight = self.in_flight.lock().expect("in-flight lock poisoned");
+            let count = in_flight.entry(self.tags.clone()).or_default();
+            *count += delta;
+            let count = *count;
+            // Routes without requests in flight would otherwise be kept forever.
+            if count == 0 {
+                in_flight.remove(&self.tags);
+            }
+            count
+        };
+        let _ = self.statsd.gauge(
+            "http.server.requests.in_flight",
+            count.to_string(),
+            &self.tags,
+        );
+    }
+}
+
+/// How a request ended, for [`RequestMetrics::finish`].
+enum Outcome {
+    /// With a response of this status code.
+    Status(u16),
+    /// Dropped before a response was produced.
+    Cancelled,
+    /// With an error from the inner service instead of a response.
+    Error,
+}
+
+/// Moves the end of `span` to now.
+///
+/// The Datadog layer ends spans when they were last exited rather than when they are closed, so
+/// spans that outlive their last poll, like those of response bodies or WebSocket messages, must be
+/// entered once more when they are done.
+pub(crate) fn touch_span(span: &Span) {
+    drop(span.enter());
+}
+
+/// Records that a request was cancelled before its response was produced.
+fn record_cancellation(span: &Span, layer: &HttpTraceLayer, method: &Method, route: &str) {
+    touch_span(span);
+    span.record("http.cancelled", true);
+    if let Some(status_code) = layer.cancelled_status_code {
+        span.record("http.status_code", status_code);
+    }
+
+    if let Some(ServerMetrics { statsd, .. }) = layer.metrics {
+        let _ = statsd.incr(
+            "http.server.request.cancelled",
+            [format!("method:{method}"), format!("route:{route}")],
+        );
+    }
+}
+
+/// Records that an extractor rejected a request.
+fn record_rejection(
+    span: &Span,
+    layer: &HttpTraceLayer,
+    method: &Method,
+    route: &str,
+    rejection: &RejectionInfo,
+) {
+    span.record("http.rejection.type", rejection.rejection_type);
+    span.record("http.rejection.message", rejection.message.as_str());
+
+    if let Some(ServerMetrics { statsd, .. }) = layer.metrics {
+        let _ = statsd.incr(
+            "http.server.request.rejected",
+            [
+                format!("rejection:{}", rejection.rejection_type),
+                format!("method:{method}"),
+                format!("route:{route}"),
+            ],
+        );
+    }
+}
+
+/// Records that a request was rejected to protect the service.
+fn record_load_rejection(
+    span: &Span,
+    layer: &HttpTraceLayer,
+    method: &Method,
+    route: &str,
+    rejection: LoadRejection,
+) {
+    span.record(format!("http.{}", rejection.as_str()).as_str(), true);
+
+    if let Some(ServerMetrics { statsd, .. }) = layer.metrics {
+        let _ = statsd.incr(
+            format!("http.server.request.{}", rejection.as_str()),
+            [format!("method:{method}"), format!("route:{route}")],
+        );
+    }
+}
+
+impl<Fut, ResBody, E> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<Response<ResBody>, E>>,
+    E: Error + 'static,
+    ResBody: Body,
+{
+    type Output = Result<Response<TracedBody<ResBody>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = this.span.enter();
+        let result = futures_util::ready!(this.inner.poll(cx));
+        *this.completed = true;
+        this.span.record(
+            "http.response.time_to_first_byte_ms",
+            this.start.elapsed().as_secs_f64() * 1000.0,
+        );
+        update_span_from_response_or_error(this.span, &result);
+
+        let load_rejection = match &result {
+            Ok(response) => response.extensions().get::<LoadRejection>().copied(),
+            Err(err) => LoadRejection::from_error(err),
+        };
+        if let Some(rejection) = load_rejection {
+            record_load_rejection(this.span, this.layer, this.method, this.route, rejection);
+        }
+        if let Some(rejection) = result
+            .as_ref()
+            .ok()
+            .and_then(|response| response.extensions().get::<RejectionInfo>())
+        {
+            record_rejection(this.span, this.layer, this.method, this.route, rejection);
+        }
+
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                if let Some(metrics) = this.metrics.take() {
+                    metrics.finish(Outcome::Error);
+                }
+                return Poll::Ready(Err(err));
+            }
+        };
+
+        let count_content_length = response_content_length(&response).is_none();
+        let mut metrics = this.metrics.take();
+        if !this.layer.trace_response_body {
+            // The request is finished as far as metrics are concerned.
+            if let Some(metrics) = metrics.take() {
+                metrics.finish(Outcome::Status(response.status().as_u16()));
+            }
+        }
+        let status_code = response.status().as_u16();
+
+        Poll::Ready(Ok(response.map(|body| {
+            TracedBody::new(
+                body,
+                this.span.clone(),
+                this.layer.trace_response_body,
+                count_content_length,
+                metrics.map(|metrics| (metrics, status_code)),
+            )
+        })))
+    }
+}
+
+pin_project! {
+    /// Response body of [`HttpTraceService`].
+    ///
+    /// When [`HttpTraceLayer::trace_response_body`] is enabled, this keeps the request span open
+    /// until the body has been fully sent, the body errors, or the client disconnects.
+    ///
+    /// Bodies without a known length are counted while streamed to record
+    /// `http.response.content_length`.
+    pub struct TracedBody<B> {
+        #[pin]
+        inner: B,
+        state: Option<BodyState>,
+    }
+
+    impl<B> PinnedDrop for TracedBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            // Dropped before the end of the stream, so the client must have gone away.
+            if let Some(state) = this.project().state.take() {
+                state.finish(true);
+            }
+        }
+    }
+}
+
+/// Tracking state for a [`TracedBody`] that is still being sent.
+struct BodyState {
+    span: Span,
+    bytes_sent: u64,
+    /// Whether the span stays open until the body is finished.
+    trace: bool,
+    /// Whether to record the bytes sent as the content length.
+    count_content_length: bool,
+    /// Request metrics to finish with the response status code, if enabled.
+    metrics: Option<(RequestMetrics, u16)>,
+}
+
+impl BodyState {
+    /// Records the final body tags on the span.
+    fn finish(self, disconnected: bool) {
+        if let Some((metrics, status_code)) = self.metrics {
+            metrics.finish(Outcome::Status(status_code));
+        }
+
+        if self.count_content_length && !disconnected {
+            self.span
+                .record("http.response.content_length", self.bytes_sent);
+        }
+
+        if !self.trace {
+            return;
+        }
+
+        touch_span(&self.span);
+        if !disconnected {
+            self.span.record("http.response.body.size", self.bytes_sent);
+        }
+        self.span
+            .record("http.response.bytes_sent", self.bytes_sent);
+        self.span.record("http.client_disconnected", disconnected);
+    }
+}
+
+impl<B> TracedBody<B>
+where
+    B: Body,
+{
+    /// Wraps a body, tracking it on `span` as configured.
+    fn new(
+        inner: B,
+        span: Span,
+        trace: bool,
+        count_content_length: bool,
+        metrics: Option<(RequestMetrics, u16)>,
+    ) -> Self {
+        let state = (trace || count_content_length || metrics.is_some()).then_some(BodyState {
+            span,
+            bytes_sent: 0,
+            trace,
+            count_content_length,
+            metrics,
+        });
+
+        // Empty bodies might never be polled, so finish them right away.
+        let state = match state {
+            Some(state) if inner.is_end_stream() => {
+                state.finish(false);
+                None
+            }
+            state => state,
+        };
+
+        Self { inner, state }
+    }
+}
+
+impl<B> Body for TracedBody<B>
+where
+    B: Body,
+    B::Error: Error,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let Some(state) = this.state.as_mut() else {
+            return this.inner.poll_frame(cx);
+        };
+
+        let result = {
+            let _guard = state.trace.then(|| state.span.enter());
+            futures_util::ready!(this.inner.as_mut().poll_frame(cx))
+        };
+
+        let finished = match &result {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    state.bytes_sent += data.remaining() as u64;
+                }
+                this.inner.is_end_stream()
+            }
+            Some(Err(err)) => {
+                crate::tracing::record_error(&state.span, err);
+                true
+            }
+            None => true,
+        };
+
+        if finished {
+            if let Some(state) = this.state.take() {
+                state.finish(false);
+            }
+        }
+
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{next_frame, stand_in_agent, with_recorder};
+    use bytes::Bytes;
+    use std::sync::OnceLock;
+    use std::{collections::VecDeque, convert::Infallible};
+    use tower::ServiceExt;
+
+    /// Body that yields one chunk per frame.
+    struct Chunks(VecDeque<&'static str>);
+
+    impl Body for Chunks {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(
+                self.0
+                    .pop_front()
+                    .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk.as_bytes())))),
+            )
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    /// Returns a response with a body streamed in two chunks.
+    async fn streaming_handler(_req: Request<String>) -> Result<Response<Chunks>, Infallible> {
+        Ok(Response::new(Chunks(VecDeque::from(["hello ", "world"]))))
+    }
+
+    #[test]
+    fn traced_body_records_bytes_sent() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new()
+                .trace_response_body(true)
+                .layer(tower::service_fn(streaming_handler));
+            let response = service.oneshot(Request::new(String::new())).await.unwrap();
+            let mut body = response.into_body();
+            while next_frame(&mut body).await.is_some() {}
+        });
+
+        assert_eq!(
+            recorder.get("http.response.body.size").as_deref(),
+            Some("11")
+        );
+        assert_eq!(
+            recorder.get("http.response.bytes_sent").as_deref(),
+            Some("11")
+        );
+        assert_eq!(
+            recorder.get("http.client_disconnected").as_deref(),
+            Some("false")
+        );
+    }
+
+    #[test]
+    fn traced_body_records_client_disconnect() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new()
+                .trace_response_body(true)
+                .layer(tower::service_fn(streaming_handler));
+            let response = service.oneshot(Request::new(String::new())).await.unwrap();
+            let mut body = response.into_body();
+            let _ = next_frame(&mut body).await;
+        });
+
+        assert_eq!(recorder.get("http.response.body.size"), None);
+        assert_eq!(
+            recorder.get("http.response.bytes_sent").as_deref(),
+            Some("6")
+        );
+        assert_eq!(
+            recorder.get("http.client_disconnected").as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn traced_body_records_errors() {
+        /// Body that fails on its first frame.
+        struct Failing;
+
+        impl Body for Failing {
+            type Data = Bytes;
+            type Error = std::io::Error;
+
+            fn poll_frame(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+            ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+                Poll::Ready(Some(Err(std::io::Error::other("connection reset"))))
+            }
+        }
+
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new()
+                .trace_response_body(true)
+                .layer(tower::service_fn(|_req: Request<String>| async {
+                    Ok::<_, Infallible>(Response::new(Failing))
+                }));
+            let response = service.oneshot(Request::new(String::new())).await.unwrap();
+            let _ = next_frame(&mut response.into_body()).await;
+        });
+
+        assert_eq!(
+            recorder.get("error.message").as_deref(),
+            Some("connection reset")
+        );
+        assert_eq!(
+            recorder.get("error.stack").as_deref(),
+            Some("connection reset")
+        );
+    }
+
+    #[test]
+    fn dropped_response_future_records_cancellation() {
+        let (recorder, _) = with_recorder(async {
+            let mut service =
+                HttpTraceLayer::new()
+                    .cancelled_status_code(498)
+                    .layer(tower::service_fn(|_req: Request<String>| {
+                        std::future::pending::<Result<Response<String>, Infallible>>()
+                    }));
+            let future = service.call(Request::new(String::new()));
+            drop(future);
+        });
+
+        assert_eq!(recorder.get("http.cancelled").as_deref(), Some("true"));
+        assert_eq!(recorder.get("http.status_code").as_deref(), Some("498"));
+    }
+
+    #[test]
+    fn dropped_response_future_has_no_status_code_by_default() {
+        let (recorder, _) = with_recorder(async {
+            let mut service =
+                HttpTraceLayer::new().layer(tower::service_fn(|_req: Request<String>| {
+                    std::future::pending::<Result<Response<String>, Infallible>>()
+                }));
+            let future = service.call(Request::new(String::new()));
+            drop(future);
+        });
+
+        assert_eq!(recorder.get("http.cancelled").as_deref(), Some("true"));
+        assert_eq!(recorder.get("http.status_code"), None);
+    }
+
+    #[test]
+    fn completed_response_future_is_not_cancelled() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new().layer(tower::service_fn(streaming_handler));
+            let _ = service.oneshot(Request::new(String::new())).await;
+        });
+
+        assert_eq!(recorder.get("http.cancelled"), None);
+    }
+
+    #[test]
+    fn request_metrics_are_emitted() {
+        static STATSD: OnceLock<StatsD> = OnceLock::new();
+        let (agent, statsd) = stand_in_agent(crate::Config::builder());
+        let statsd = STATSD.get_or_init(|| statsd);
+
+        let _ = with_recorder(async {
+            let service = HttpTraceLayer::new()
+                .metrics(statsd)
+                .layer(tower::service_fn(streaming_handler));
+            let _ = service
+                .oneshot(
+                    Request::post("/merchants/abc123")
+                        .body(String::new())
+                        .unwrap(),
+                )
+                .await;
+        });
+
+        let packets = agent.drain();
+
+        assert!(
+            packets
+                .iter()
+                .any(|p| p.starts_with("http.server.requests.in_flight:1|g|"))
+        );
+        assert!(
+            packets
+                .iter()
+                .any(|p| p.starts_with("http.server.requests.in_flight:0|g|"))
+        );
+        assert!(
+            packets
+                .iter()
+                .any(|p| p.starts_with("http.server.request.duration:"))
+        );
+        assert!(packets.iter().any(|p| {
+            p.starts_with("http.server.request.count:1|c|")
+                && p.contains("method:POST,route:/merchants/?,status_class:2xx,status_code:200")
+        }));
+    }
+
+    #[test]
+    fn errored_request_metrics_have_no_status() {
+        static STATSD: OnceLock<StatsD> = OnceLock::new();
+        let (agent, statsd) = stand_in_agent(crate::Config::builder());
+        let statsd = STATSD.get_or_init(|| statsd);
+        let layer = HttpTraceLayer::new().metrics(statsd);
+
+        let _ = with_recorder(async {
+            let service = layer
+                .clone()
+                .layer(tower::service_fn(|_req: Request<String>| async {
+                    Err::<Response<String>, _>(std::io::Error::other("connection reset"))
+                }));
+            let _ = service.oneshot(Request::new(String::new())).await;
+        });
+
+        let packets = agent.drain();
+
+        assert!(packets.iter().any(|p| {
+            p.starts_with("http.server.request.count:1|c|")
+                && p.contains("method:GET,route:/,error:true")
+        }));
+        assert!(!packets.iter().any(|p| p.contains("status_code:")));
+        let in_flight = &layer.metrics.as_ref().unwrap().in_flight;
+        assert!(in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resource_namer_overrides_resource() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new()
+                .resource_namer(|parts: &Parts| {
+                    let method = parts.headers.get("X-RPC-Method")?.to_str().ok()?;
+                    Some(format!("RPC {method}"))
+                })
+                .layer(tower::service_fn(streaming_handler));
+            let request = Request::post("/rpc")
+                .header("X-RPC-Method", "getMerchant")
+                .body(String::new())
+                .unwrap();
+            let _ = service.oneshot(request).await;
+        });
+
+        assert_eq!(recorder.get("resource").as_deref(), Some("RPC getMerchant"));
+    }
+
+    #[test]
+    fn load_rejection_from_error() {
+        assert_eq!(
+            LoadRejection::from_error(&Elapsed::new()),
+            Some(LoadRejection::Timeout)
+        );
+        assert_eq!(
+            LoadRejection::from_error(&Overloaded::new()),
+            Some(LoadRejection::Shed)
+        );
+        let boxed: tower::BoxError = Box::new(Overloaded::new());
+        assert_eq!(
+            LoadRejection::from_error(&*boxed),
+            Some(LoadRejection::Shed)
+        );
+        assert_eq!(LoadRejection::from_error(&std::fmt::Error), None);
+    }
+
+    #[test]
+    fn timeout_errors_are_tagged() {
+        let (recorder, _) = with_recorder(async {
+            let service =
+                HttpTraceLayer::new().layer(tower::service_fn(|_req: Request<String>| async {
+                    Err::<Response<String>, _>(Elapsed::new())
+                }));
+            let _ = service.oneshot(Request::new(String::new())).await;
+        });
+
+        assert_eq!(recorder.get("http.timeout").as_deref(), Some("true"));
+        assert_eq!(
+            recorder.get("error.type").as_deref(),
+            Some("tower::timeout::error::Elapsed")
+        );
+        assert_eq!(
+            recorder.get("error.message").as_deref(),
+            Some("request timed out")
+        );
+    }
+
+    #[test]
+    fn response_body_is_not_traced_by_default() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new().layer(tower::service_fn(streaming_handler));
+            let response = service.oneshot(Request::new(String::new())).await.unwrap();
+            let mut body = response.into_body();
+            while next_frame(&mut body).await.is_some() {}
+        });
+
+        assert_eq!(recorder.get("http.status_code").as_deref(), Some("200"));
+        assert_eq!(recorder.get("http.response.bytes_sent"), None);
+    }
+
+    #[test]
+    fn streamed_response_content_length_is_counted() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new().layer(tower::service_fn(streaming_handler));
+            let response = service.oneshot(Request::new(String::new())).await.unwrap();
+            let mut body = response.into_body();
+            while next_frame(&mut body).await.is_some() {}
+        });
+
+        assert_eq!(
+            recorder.get("http.response.content_length").as_deref(),
+            Some("11")
+        );
+        assert!(
+            recorder
+                .get("http.response.time_to_first_byte_ms")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn content_lengths_are_recorded_from_headers() {
+        let (recorder, _) = with_recorder(async {
+            let service =
+                HttpTraceLayer::new().layer(tower::service_fn(|_req: Request<String>| async {
+                    Response::builder()
+                        .header(header::CONTENT_LENGTH, "3")
+                        .body(String::from("abc"))
+                }));
+            let request = Request::builder()
+                .header(header::CONTENT_LENGTH, "42")
+                .body(String::new())
+                .unwrap();
+            let _ = service.oneshot(request).await;
+        });
+
+        assert_eq!(
+            recorder.get("http.request.content_length").as_deref(),
+            Some("42")
+        );
+        assert_eq!(
+            recorder.get("http.response.content_length").as_deref(),
+            Some("3")
+        );
+    }
+
+    #[test]
+    fn requests_without_route_use_path_group() {
+        let (recorder, _) = with_recorder(async {
+            let service = HttpTraceLayer::new().layer(tower::service_fn(streaming_handler));
+            let mut request = Request::post("/webhooks/abc123")
+                .body(String::new())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(SocketAddr::from(([10, 0, 0, 1], 4321)));
+            let _ = service.oneshot(request).await;
+        });
+
+        assert_eq!(recorder.get("operation").as_deref(), Some("http.request"));
+        assert_eq!(
+            recorder.get("resource").as_deref(),
+            Some("POST /webhooks/?")
+        );
+        assert_eq!(recorder.get("http.route"), None);
+        assert_eq!(recorder.get("http.client.ip").as_deref(), Some("10.0.0.1"));
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(clippy::needless_doctest_main)]
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "agentless")]
+mod agentless;
+
 #[cfg(feature = "aws_ecs")]
 mod aws;
 
@@ -12,9 +15,21 @@ pub mod http;
 pub mod statsd;
 pub mod tracing;
 
+#[cfg(unix)]
+mod uds;
+
+#[cfg(feature = "actix")]
+pub mod actix;
+
 #[cfg(feature = "axum")]
 pub mod axum;
 
+#[cfg(feature = "tonic")]
+pub mod tonic;
+
+#[cfg(test)]
+mod test_util;
+
 pub use config::Config;
 
 #[cfg(feature = "sqlx")]
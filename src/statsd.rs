@@ -2,18 +2,50 @@
 //!
 //! This mostly proxies to the [`dogstatsd`] crate, which has more documentation on the API.
 
-use std::{ops::Deref, sync::OnceLock};
+use crate::config::AgentAddress;
+use dogstatsd::{
+    DogstatsdError, DogstatsdResult, EventOptions, ServiceCheckOptions, ServiceStatus,
+};
+use std::{borrow::Cow, future::Future, sync::OnceLock, time::Instant};
+
+mod aggregation;
+mod cardinality;
+mod tags;
+
+use aggregation::{Aggregation, Target, Value};
+use cardinality::CardinalityLimiter;
+pub use tags::{Tag, Tags};
 
 /// Global StatsD instance, if used.
 static GLOBAL_STATSD: OnceLock<StatsD> = OnceLock::new();
 
+/// The counter incremented whenever the cardinality limit replaces tag values, tagged with the
+/// name of the limited `metric`.
+pub const CARDINALITY_LIMITED_METRIC: &str = "komoju_datadog.cardinality_limited";
+
 /// A client for submitting metrics to the Datadog agent.
 ///
-/// Includes default tags for unified service tagging.
+/// Includes default tags for unified service tagging, along with the [configured
+/// tags](crate::Config::tags).
 ///
 /// A single, global client is recommended, but a client can also be created and passed around
 /// where needed.
 ///
+/// When [`metrics_enabled`](crate::Config::metrics_enabled) is off, the client discards all
+/// metrics, so call sites don't need to check the config.
+///
+/// To keep IDs in tags from creating too many custom metrics, a
+/// [`metrics_cardinality_limit`](crate::Config::metrics_cardinality_limit) can be set, beyond which
+/// metrics with more distinct tag sets get new tag values replaced with `other`. A warning is
+/// logged once per metric, and [`CARDINALITY_LIMITED_METRIC`] is incremented every time.
+///
+/// By default, every metric is sent as its own datagram on the caller's thread. With a
+/// [`metrics_aggregation_interval`](crate::Config::metrics_aggregation_interval), calls only
+/// update in-memory state instead: counts are summed, gauges keep their last value and sets their
+/// distinct values, while other metrics are buffered. A background thread sends them all at every
+/// interval, batched into datagrams that fit the MTU. Call [`StatsD::flush`] before exiting to
+/// send what's left. Events and service checks are always sent right away.
+///
 /// # Examples
 ///
 /// ```
@@ -24,9 +56,21 @@ static GLOBAL_STATSD: OnceLock<StatsD> = OnceLock::new();
 ///
 /// // From anywhere in the service.
 /// let _ = StatsD::global().incr("my_counter", &["tag:counter"]);
+///
+/// // Or with typed tags, normalized for Datadog.
+/// let _ = StatsD::global().incr("my_counter", [komoju_datadog::tag!("tag", "counter")]);
 /// ```
+#[derive(Debug)]
 pub struct StatsD {
-    inner: dogstatsd::Client,
+    /// The underlying client, or `None` if metrics are disabled.
+    inner: Option<dogstatsd::Client>,
+    /// The limit on distinct tag sets per metric, if any.
+    limiter: Option<CardinalityLimiter>,
+    /// The aggregation that metrics are sent through, if enabled.
+    aggregation: Option<Aggregation>,
+    /// The aggregator that the client sends to in agentless mode.
+    #[cfg(feature = "agentless")]
+    aggregator: Option<crate::agentless::MetricsAggregator>,
 }
 
 impl StatsD {
@@ -36,21 +80,99 @@ impl StatsD {
     /// the right places or use a static [`OnceLock`] to make it accessible from anywhere.
     /// See [`StatsD::init_global`] for a shortcut version.
     ///
+    /// Unix datagram sockets are supported natively, while metrics for Unix stream sockets are
+    /// relayed through a background thread.
+    ///
+    /// In [agentless mode](crate::Config::api_key), metrics are aggregated in a background thread
+    /// instead, and submitted to Datadog every 10 seconds. Events and service checks are dropped
+    /// in that mode.
+    ///
     /// # Panics
     ///
-    /// Can panic if bad options are passed.
+    /// Can panic if bad options are passed, or if Unix sockets are used on other platforms.
     pub fn new(config: &crate::Config) -> Self {
-        let inner = dogstatsd::Client::new(
-            dogstatsd::OptionsBuilder::new()
-                .to_addr(config.metrics_agent_url.clone())
-                .default_tag(format!("service:{}", config.service))
-                .default_tag(format!("env:{}", config.env))
-                .default_tag(format!("version:{}", config.version))
-                .build(),
+        if !config.metrics_enabled {
+            return Self::disabled();
+        }
+
+        let mut options = dogstatsd::OptionsBuilder::new();
+
+        #[cfg(feature = "agentless")]
+        let aggregator = config.api_key.as_ref().map(|api_key| {
+            let intake = crate::agentless::Intake::new(api_key.expose(), &config.site);
+            crate::agentless::MetricsAggregator::start(intake)
+                .expect("failed to start agentless metrics aggregator")
+        });
+        #[cfg(feature = "agentless")]
+        let target = match &aggregator {
+            Some(aggregator) => Target::Udp(aggregator.addr().to_string()),
+            None => agent_target(&config.metrics_agent_url),
+        };
+        #[cfg(not(feature = "agentless"))]
+        let target = agent_target(&config.metrics_agent_url);
+
+        match &target {
+            Target::Udp(addr) => options.to_addr(addr.clone()),
+            Target::Unix(path) => options.socket_path(Some(path.to_string_lossy().into_owned())),
+        };
+
+        let default_tags = [
+            format!("service:{}", config.service),
+            format!("env:{}", config.env),
+            format!("version:{}", config.version),
+        ]
+        .into_iter()
+        .chain(
+            config
+                .tags
+                .iter()
+                .map(|(key, value)| format!("{key}:{value}")),
         )
-        .expect("failed to create DogstatsD client");
+        .collect::<Vec<_>>();
+        for tag in &default_tags {
+            options.default_tag(tag.clone());
+        }
+
+        let inner =
+            dogstatsd::Client::new(options.build()).expect("failed to create DogstatsD client");
+        let aggregation = (!config.metrics_aggregation_interval.is_zero()).then(|| {
+            Aggregation::start(target, default_tags, config.metrics_aggregation_interval)
+                .expect("failed to start metrics aggregation")
+        });
+
+        Self {
+            inner: Some(inner),
+            limiter: CardinalityLimiter::new(config.metrics_cardinality_limit),
+            aggregation,
+            #[cfg(feature = "agentless")]
+            aggregator,
+        }
+    }
 
-        Self { inner }
+    /// Creates a client that discards all metrics, without opening a socket.
+    pub fn disabled() -> Self {
+        Self {
+            inner: None,
+            limiter: None,
+            aggregation: None,
+            #[cfg(feature = "agentless")]
+            aggregator: None,
+        }
+    }
+
+    /// Returns whether metrics are sent, as opposed to discarded.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Returns the underlying client, or `None` if metrics are disabled, e.g. for [`dogstatsd`]
+    /// API that `StatsD` doesn't wrap.
+    ///
+    /// Metrics sent through the client bypass the
+    /// [cardinality limit](crate::Config::metrics_cardinality_limit) and
+    /// [aggregation](crate::Config::metrics_aggregation_interval).
+    pub fn client(&self) -> Option<&dogstatsd::Client> {
+        self.inner.as_ref()
     }
 
     /// Creates a new global StatsD client.
@@ -76,12 +198,583 @@ impl StatsD {
     pub fn try_global() -> Option<&'static Self> {
         GLOBAL_STATSD.get()
     }
+
+    /// Sends metrics that are [aggregated](crate::Config::metrics_aggregation_interval), and
+    /// submits those aggregated in [agentless mode](crate::Config::api_key), e.g. before a batch
+    /// job exits. Does nothing otherwise, as metrics are sent to the agent right away.
+    pub fn flush(&self) {
+        if let Some(aggregation) = &self.aggregation {
+            aggregation.flush();
+        }
+        #[cfg(feature = "agentless")]
+        if let Some(aggregator) = &self.aggregator {
+            aggregator.flush();
+        }
+    }
+
+    /// Returns the client, the name and the tags to send a metric with, or `None` if metrics are
+    /// disabled.
+    ///
+    /// Tags are subject to the [cardinality limit](crate::Config::metrics_cardinality_limit),
+    /// and [`CARDINALITY_LIMITED_METRIC`] is incremented whenever it replaces tag values. Without
+    /// a limit or aggregation, the caller's tags are passed through, only skipping empty ones.
+    fn metric<'a, I, S, T>(&self, stat: S, tags: I) -> Option<Metric<'_, 'a, I::IntoIter>>
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        let inner = self.inner.as_ref()?;
+        let stat = stat.into();
+        if self.limiter.is_none() && self.aggregation.is_none() {
+            return Some((inner, stat, MetricTags::Caller(tags.into_iter())));
+        }
+
+        let mut tags = tags
+            .into_iter()
+            .filter(|tag| !tag.as_ref().is_empty())
+            .map(|tag| tag.as_ref().to_string())
+            .collect();
+        if let Some(limiter) = &self.limiter {
+            let limited;
+            (tags, limited) = limiter.limit(&stat, tags);
+            if limited {
+                let tags = vec![format!("metric:{stat}")];
+                match &self.aggregation {
+                    Some(aggregation) => {
+                        aggregation.add(CARDINALITY_LIMITED_METRIC, tags, Value::Count(1));
+                    }
+                    None => {
+                        let _ = inner.incr(CARDINALITY_LIMITED_METRIC, tags);
+                    }
+                }
+            }
+        }
+        Some((inner, stat, MetricTags::Collected(tags.into_iter())))
+    }
+
+    /// Sends a metric, either to the [aggregation](crate::Config::metrics_aggregation_interval)
+    /// or right away through `direct`.
+    fn send<'a, I, S, T>(
+        &self,
+        stat: S,
+        tags: I,
+        value: Value<'_>,
+        direct: impl FnOnce(
+            &dogstatsd::Client,
+            Cow<'a, str>,
+            MetricTags<I::IntoIter>,
+        ) -> DogstatsdResult,
+    ) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        let Some((inner, stat, tags)) = self.metric(stat, tags) else {
+            return Ok(());
+        };
+        match &self.aggregation {
+            Some(aggregation) => {
+                aggregation.add(&stat, tags.into_vec(), value);
+                Ok(())
+            }
+            None => direct(inner, stat, tags),
+        }
+    }
+
+    /// Increments a counter, see [`dogstatsd::Client::incr`].
+    pub fn incr<'a, I, S, T>(&self, stat: S, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.send(stat, tags, Value::Count(1), |inner, stat, tags| {
+            inner.incr(stat, tags)
+        })
+    }
+
+    /// Increments a counter by a value, see [`dogstatsd::Client::incr_by_value`].
+    pub fn incr_by_value<'a, I, S, T>(&self, stat: S, value: i64, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.send(stat, tags, Value::Count(value), |inner, stat, tags| {
+            inner.incr_by_value(stat, value, tags)
+        })
+    }
+
+    /// Decrements a counter, see [`dogstatsd::Client::decr`].
+    pub fn decr<'a, I, S, T>(&self, stat: S, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.send(stat, tags, Value::Count(-1), |inner, stat, tags| {
+            inner.decr(stat, tags)
+        })
+    }
+
+    /// Decrements a counter by a value, see [`dogstatsd::Client::decr_by_value`].
+    pub fn decr_by_value<'a, I, S, T>(&self, stat: S, value: i64, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.send(stat, tags, Value::Count(-value), |inner, stat, tags| {
+            inner.decr_by_value(stat, value, tags)
+        })
+    }
+
+    /// Adds to a counter, see [`dogstatsd::Client::count`].
+    pub fn count<'a, I, S, T>(&self, stat: S, count: i64, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.send(stat, tags, Value::Count(count), |inner, stat, tags| {
+            inner.count(stat, count, tags)
+        })
+    }
+
+    /// Times a block of code, see [`dogstatsd::Client::time`]. The block always runs, even if
+    /// metrics are disabled.
+    pub fn time<'a, F, O, I, S, T>(
+        &self,
+        stat: S,
+        tags: I,
+        block: F,
+    ) -> Result<O, (O, DogstatsdError)>
+    where
+        F: FnOnce() -> O,
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        match (self.metric(stat, tags), &self.aggregation) {
+            (Some((_, stat, tags)), Some(aggregation)) => {
+                let start = Instant::now();
+                let output = block();
+                aggregation.add(&stat, tags.into_vec(), Value::Timing(elapsed_ms(start)));
+                Ok(output)
+            }
+            (Some((inner, stat, tags)), None) => inner.time(stat, tags, block),
+            (None, _) => Ok(block()),
+        }
+    }
+
+    /// Times an async block of code, see [`dogstatsd::Client::async_time`]. The block always
+    /// runs, even if metrics are disabled.
+    pub async fn async_time<'a, F, Fut, O, I, S, T>(
+        &self,
+        stat: S,
+        tags: I,
+        block: F,
+    ) -> Result<O, (O, DogstatsdError)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = O>,
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        match (self.metric(stat, tags), &self.aggregation) {
+            (Some((_, stat, tags)), Some(aggregation)) => {
+                let start = Instant::now();
+                let output = block().await;
+                aggregation.add(&stat, tags.into_vec(), Value::Timing(elapsed_ms(start)));
+                Ok(output)
+            }
+            (Some((inner, stat, tags)), None) => inner.async_time(stat, tags, block).await,
+            (None, _) => Ok(block().await),
+        }
+    }
+
+    /// Sends a timing in milliseconds, see [`dogstatsd::Client::timing`].
+    pub fn timing<'a, I, S, T>(&self, stat: S, ms: i64, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.send(stat, tags, Value::Timing(ms), |inner, stat, tags| {
+            inner.timing(stat, ms, tags)
+        })
+    }
+
+    /// Sets a gauge, see [`dogstatsd::Client::gauge`].
+    pub fn gauge<'a, I, S, SS, T>(&self, stat: S, val: SS, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        SS: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        let val = val.into();
+        self.send(stat, tags, Value::Gauge(&val), |inner, stat, tags| {
+            inner.gauge(stat, val.as_ref(), tags)
+        })
+    }
+
+    /// Adds a value to a histogram, see [`dogstatsd::Client::histogram`].
+    pub fn histogram<'a, I, S, SS, T>(&self, stat: S, val: SS, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        SS: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        let val = val.into();
+        self.send(stat, tags, Value::Histogram(&val), |inner, stat, tags| {
+            inner.histogram(stat, val.as_ref(), tags)
+        })
+    }
+
+    /// Adds a value to a distribution, see [`dogstatsd::Client::distribution`].
+    pub fn distribution<'a, I, S, SS, T>(&self, stat: S, val: SS, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        SS: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        let val = val.into();
+        self.send(
+            stat,
+            tags,
+            Value::Distribution(&val),
+            |inner, stat, tags| inner.distribution(stat, val.as_ref(), tags),
+        )
+    }
+
+    /// Adds a value to a set, see [`dogstatsd::Client::set`].
+    pub fn set<'a, I, S, SS, T>(&self, stat: S, val: SS, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        SS: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        let val = val.into();
+        self.send(stat, tags, Value::Set(&val), |inner, stat, tags| {
+            inner.set(stat, val.as_ref(), tags)
+        })
+    }
+
+    /// Reports the status of a service, see [`dogstatsd::Client::service_check`].
+    pub fn service_check<'a, I, S, T>(
+        &self,
+        stat: S,
+        val: ServiceStatus,
+        tags: I,
+        options: Option<ServiceCheckOptions>,
+    ) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.inner.as_ref().map_or(Ok(()), |inner| {
+            inner.service_check(stat, val, tags, options)
+        })
+    }
+
+    /// Sends an event, see [`dogstatsd::Client::event`].
+    pub fn event<'a, I, S, SS, T>(&self, title: S, text: SS, tags: I) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        SS: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.inner
+            .as_ref()
+            .map_or(Ok(()), |inner| inner.event(title, text, tags))
+    }
+
+    /// Sends an event with options, see [`dogstatsd::Client::event_with_options`].
+    pub fn event_with_options<'a, I, S, SS, T>(
+        &self,
+        title: S,
+        text: SS,
+        tags: I,
+        options: Option<EventOptions<'a>>,
+    ) -> DogstatsdResult
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<Cow<'a, str>>,
+        SS: Into<Cow<'a, str>>,
+        T: AsRef<str>,
+    {
+        self.inner.as_ref().map_or(Ok(()), |inner| {
+            inner.event_with_options(title, text, tags, options)
+        })
+    }
+}
+
+/// A metric to send: the client, the metric name and its tags, see [`StatsD::metric`].
+type Metric<'c, 'a, I> = (&'c dogstatsd::Client, Cow<'a, str>, MetricTags<I>);
+
+/// The tags to send a metric with, see [`StatsD::metric`].
+enum MetricTags<I> {
+    /// The caller's tags, passed through except for empty ones, e.g. [`Tag`]s that normalized to
+    /// nothing.
+    Caller(I),
+    /// The caller's tags, collected for the cardinality limit or aggregation.
+    Collected(std::vec::IntoIter<String>),
+}
+
+/// A tag of [`MetricTags`].
+enum MetricTag<T> {
+    Caller(T),
+    Collected(String),
+}
+
+impl<I, T> MetricTags<I>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+{
+    /// Returns the tags as strings, e.g. for the aggregation.
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::Caller(tags) => tags
+                .filter(|tag| !tag.as_ref().is_empty())
+                .map(|tag| tag.as_ref().to_string())
+                .collect(),
+            Self::Collected(tags) => tags.collect(),
+        }
+    }
 }
 
-impl Deref for StatsD {
-    type Target = dogstatsd::Client;
+impl<I, T> Iterator for MetricTags<I>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+{
+    type Item = MetricTag<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Caller(tags) => tags
+                .find(|tag| !tag.as_ref().is_empty())
+                .map(MetricTag::Caller),
+            Self::Collected(tags) => tags.next().map(MetricTag::Collected),
+        }
+    }
+}
+
+impl<T: AsRef<str>> AsRef<str> for MetricTag<T> {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Caller(tag) => tag.as_ref(),
+            Self::Collected(tag) => tag,
+        }
+    }
+}
+
+/// Returns where to send metrics for an agent URL, relaying them for Unix stream sockets.
+///
+/// # Panics
+///
+/// Panics if a Unix stream socket is used on other platforms.
+fn agent_target(metrics_agent_url: &str) -> Target {
+    match AgentAddress::parse(metrics_agent_url) {
+        Ok(AgentAddress::Unix(path) | AgentAddress::Unixgram(path)) => {
+            Target::Unix(path.to_path_buf())
+        }
+        #[cfg(unix)]
+        Ok(AgentAddress::Unixstream(path)) => {
+            let addr = crate::uds::relay_datagrams_to_stream(path)
+                .expect("failed to relay metrics to DogStatsD socket");
+            Target::Udp(addr.to_string())
+        }
+        #[cfg(not(unix))]
+        Ok(AgentAddress::Unixstream(_)) => {
+            panic!("Unix stream sockets are not supported on this platform")
+        }
+        _ => Target::Udp(metrics_agent_url.to_string()),
+    }
+}
+
+/// Returns the milliseconds elapsed since `start`.
+fn elapsed_ms(start: Instant) -> i64 {
+    i64::try_from(start.elapsed().as_millis()).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tag, test_util::stand_in_agent};
+
+    #[test]
+    fn disabled_client_discards_metrics() {
+        let config = crate::Config::builder()
+            .metrics_enabled(false)
+            .build()
+            .unwrap();
+        let statsd = StatsD::new(&config);
+        assert!(!statsd.is_enabled());
+        assert!(statsd.incr("payments.captured", ["method:card"]).is_ok());
+        assert!(statsd.gauge("payments.queue", "3", &[] as &[&str]).is_ok());
+        assert!(matches!(
+            statsd.time("payments.capture", ["method:card"], || 42),
+            Ok(42)
+        ));
+        statsd.flush();
+    }
+
+    #[test]
+    fn methods_match_dogstatsd() {
+        fn same<T>(_: T, _: T) {}
+
+        let statsd = StatsD::disabled();
+        // Only type checked, so that wrappers whose signatures drift from the client's fail to
+        // compile.
+        let _ = |client: &dogstatsd::Client| {
+            macro_rules! both {
+                ($method:ident($($arg:expr),*)) => {
+                    same(statsd.$method($($arg),*), client.$method($($arg),*))
+                };
+            }
+
+            both!(incr("a", ["t:1"]));
+            both!(incr_by_value("a", 2, ["t:1"]));
+            both!(decr("a", ["t:1"]));
+            both!(decr_by_value("a", 2, ["t:1"]));
+            both!(count("a", 2, ["t:1"]));
+            both!(time("a", ["t:1"], || 42));
+            both!(timing("a", 42, ["t:1"]));
+            both!(gauge("a", "42", ["t:1"]));
+            both!(histogram("a", "42", ["t:1"]));
+            both!(distribution("a", "42", ["t:1"]));
+            both!(set("a", "42", ["t:1"]));
+            both!(service_check("a", ServiceStatus::OK, ["t:1"], None));
+            both!(event("title", "text", ["t:1"]));
+            both!(event_with_options("title", "text", ["t:1"], None));
+            let _future = async {
+                same(
+                    statsd.async_time("a", ["t:1"], || async { 42 }).await,
+                    client.async_time("a", ["t:1"], || async { 42 }).await,
+                );
+            };
+        };
+        assert!(statsd.client().is_none());
+    }
+
+    #[test]
+    fn limited_tags_are_reported() {
+        let (agent, statsd) = stand_in_agent(
+            crate::Config::builder()
+                .service("payments")
+                .metrics_cardinality_limit(1),
+        );
+
+        statsd
+            .incr("payments.captured", [tag!("merchant", "a")])
+            .unwrap();
+        statsd
+            .incr("payments.captured", [tag!("merchant", "b")])
+            .unwrap();
+
+        assert!(
+            agent
+                .recv()
+                .starts_with("payments.captured:1|c|#merchant:a,")
+        );
+        assert!(
+            agent
+                .recv()
+                .starts_with("komoju_datadog.cardinality_limited:1|c|#metric:payments.captured,")
+        );
+        assert!(
+            agent
+                .recv()
+                .starts_with("payments.captured:1|c|#merchant:other,")
+        );
+    }
+
+    #[test]
+    fn tags_are_passed_through_without_a_limit() {
+        let (agent, statsd) = stand_in_agent(crate::Config::builder().service("payments"));
+        assert!(statsd.limiter.is_none());
+
+        let tags = [tag!("merchant", "a")];
+        assert!(matches!(
+            statsd.metric("payments.captured", &tags),
+            Some((_, _, MetricTags::Caller(_)))
+        ));
+
+        for merchant in ["a", "b", "c"] {
+            statsd
+                .incr("payments.captured", [tag!("merchant", merchant)])
+                .unwrap();
+            assert!(
+                agent
+                    .recv()
+                    .starts_with(&format!("payments.captured:1|c|#merchant:{merchant},"))
+            );
+        }
+    }
+
+    #[test]
+    fn empty_tags_are_not_sent() {
+        // Both with tags passed through and collected for the cardinality limit.
+        for limit in [0, 10] {
+            let (agent, statsd) = stand_in_agent(
+                crate::Config::builder()
+                    .service("payments")
+                    .metrics_cardinality_limit(limit),
+            );
+
+            statsd
+                .incr("payments.captured", [tag!("42"), tag!("method", "card")])
+                .unwrap();
+
+            assert!(
+                agent
+                    .recv()
+                    .starts_with("payments.captured:1|c|#method:card,service:payments")
+            );
+        }
+    }
+
+    #[test]
+    fn aggregated_metrics_are_sent_on_flush() {
+        let (agent, statsd) = stand_in_agent(
+            crate::Config::builder()
+                .service("payments")
+                .env("production")
+                .version("1.2.3")
+                .metrics_aggregation_interval(std::time::Duration::from_secs(3600)),
+        );
+
+        for _ in 0..3 {
+            statsd.incr("payments.captured", ["method:card"]).unwrap();
+        }
+        statsd
+            .set("payments.merchants", "m_42", ["method:card"])
+            .unwrap();
+        statsd
+            .set("payments.merchants", "m_42", ["method:card"])
+            .unwrap();
+        statsd.flush();
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+        let datagram = agent.recv();
+        let mut lines = datagram.lines().collect::<Vec<_>>();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            [
+                "payments.captured:3|c|#method:card,service:payments,env:production,version:1.2.3",
+                "payments.merchants:m_42|s|#method:card,service:payments,env:production,version:1.2.3",
+            ]
+        );
     }
 }
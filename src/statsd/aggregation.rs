@@ -0,0 +1,344 @@
+//! Client-side aggregation and batching of metrics.
+//!
+//! Callers only update in-memory state, while a background thread sends it to the agent at every
+//! interval, packing as many metrics per datagram as fit.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::UdpSocket,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, RecvTimeoutError, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The maximum size of a UDP datagram, leaving room for headers within a typical 1500 byte MTU.
+const MAX_UDP_DATAGRAM_SIZE: usize = 1432;
+
+/// The maximum size of a Unix socket datagram, as accepted by the agent.
+const MAX_UDS_DATAGRAM_SIZE: usize = 8192;
+
+/// The number of buffered metrics beyond which they are sent before the interval ends.
+const MAX_BUFFERED_METRICS: usize = 10_000;
+
+/// How long [`Aggregation::flush`] waits for pending metrics to be sent.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to send metrics.
+#[derive(Clone, Debug)]
+pub(super) enum Target {
+    /// A UDP address.
+    Udp(String),
+    /// A Unix datagram socket.
+    Unix(PathBuf),
+}
+
+/// A metric value, as passed to [`Aggregation::add`].
+#[derive(Debug)]
+pub(super) enum Value<'a> {
+    Count(i64),
+    Gauge(&'a str),
+    Set(&'a str),
+    Histogram(&'a str),
+    Distribution(&'a str),
+    Timing(i64),
+}
+
+/// A message to the sender thread.
+enum Message {
+    /// Sends all pending metrics, then acknowledges through the sender, if any.
+    Flush(Option<mpsc::Sender<()>>),
+}
+
+/// Aggregates counts, gauges and sets, and buffers other metrics, sending them all at every
+/// interval.
+#[derive(Debug)]
+pub(super) struct Aggregation {
+    pending: Arc<Mutex<Pending>>,
+    sender: SyncSender<Message>,
+}
+
+/// A metric name with its tags, sorted so that their order does not matter.
+type Context = (String, Vec<String>);
+
+/// Metrics waiting to be sent.
+#[derive(Debug, Default)]
+struct Pending {
+    counts: HashMap<Context, i64>,
+    /// The last value of each gauge.
+    gauges: HashMap<Context, String>,
+    sets: HashMap<Context, HashSet<String>>,
+    /// Other metrics, as formatted lines without tags.
+    buffered: Vec<(String, Vec<String>)>,
+}
+
+impl Pending {
+    fn len(&self) -> usize {
+        self.counts.len() + self.gauges.len() + self.sets.len() + self.buffered.len()
+    }
+}
+
+impl Aggregation {
+    /// Starts the thread sending metrics to `target` every `interval`, with `default_tags` added
+    /// to every metric.
+    pub(super) fn start(
+        target: Target,
+        default_tags: Vec<String>,
+        interval: Duration,
+    ) -> io::Result<Self> {
+        let transport = Transport::new(target)?;
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (sender, receiver) = mpsc::sync_channel(1);
+
+        let thread_pending = pending.clone();
+        thread::Builder::new()
+            .name("dd-metrics-sender".into())
+            .spawn(move || {
+                let default_tags = default_tags.join(",");
+                let mut next_flush = Instant::now() + interval;
+                loop {
+                    let timeout = next_flush.saturating_duration_since(Instant::now());
+                    let ack = match receiver.recv_timeout(timeout) {
+                        Ok(Message::Flush(ack)) => ack,
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            send(&thread_pending, &transport, &default_tags);
+                            return;
+                        }
+                    };
+
+                    send(&thread_pending, &transport, &default_tags);
+                    if Instant::now() >= next_flush {
+                        next_flush = Instant::now() + interval;
+                    }
+                    if let Some(ack) = ack {
+                        let _ = ack.send(());
+                    }
+                }
+            })?;
+
+        Ok(Self { pending, sender })
+    }
+
+    /// Adds a metric to be sent at the end of the interval.
+    pub(super) fn add(&self, name: &str, mut tags: Vec<String>, value: Value<'_>) {
+        tags.sort_unstable();
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        let context = (name.to_string(), tags);
+        match value {
+            Value::Count(count) => *pending.counts.entry(context).or_default() += count,
+            Value::Gauge(value) => {
+                pending.gauges.insert(context, value.to_string());
+            }
+            Value::Set(value) => {
+                pending
+                    .sets
+                    .entry(context)
+                    .or_default()
+                    .insert(value.to_string());
+            }
+            Value::Histogram(value) => pending.buffered.push(line(context, value, "h")),
+            Value::Distribution(value) => pending.buffered.push(line(context, value, "d")),
+            Value::Timing(ms) => pending.buffered.push(line(context, ms, "ms")),
+        }
+
+        if pending.len() >= MAX_BUFFERED_METRICS {
+            let _ = self.sender.try_send(Message::Flush(None));
+        }
+    }
+
+    /// Sends all pending metrics, waiting until done or timed out.
+    pub(super) fn flush(&self) {
+        let (ack, done) = mpsc::channel();
+        if self.sender.send(Message::Flush(Some(ack))).is_ok() {
+            let _ = done.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+}
+
+/// Formats a metric line without tags, returned along with the tags.
+fn line((name, tags): Context, value: impl std::fmt::Display, kind: &str) -> (String, Vec<String>) {
+    (format!("{name}:{value}|{kind}"), tags)
+}
+
+/// Takes all pending metrics and sends them in as few datagrams as possible.
+fn send(pending: &Mutex<Pending>, transport: &Transport, default_tags: &str) {
+    let pending = std::mem::take(&mut *pending.lock().unwrap_or_else(|err| err.into_inner()));
+
+    let lines = pending
+        .counts
+        .into_iter()
+        .map(|(context, count)| line(context, count, "c"))
+        .chain(
+            pending
+                .gauges
+                .into_iter()
+                .map(|(context, value)| line(context, value, "g")),
+        )
+        .chain(pending.sets.into_iter().flat_map(|(context, values)| {
+            values
+                .into_iter()
+                .map(move |value| line(context.clone(), value, "s"))
+        }))
+        .chain(pending.buffered)
+        .map(|(mut line, tags)| {
+            let tags = tags.join(",");
+            let separator = if tags.is_empty() || default_tags.is_empty() {
+                ""
+            } else {
+                ","
+            };
+            if !tags.is_empty() || !default_tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags);
+                line.push_str(separator);
+                line.push_str(default_tags);
+            }
+            line
+        });
+
+    for datagram in batch(lines, transport.max_datagram_size()) {
+        transport.send(datagram.as_bytes());
+    }
+}
+
+/// Joins lines with newlines into datagrams of at most `max_size` bytes, unless a single line is
+/// larger.
+fn batch(lines: impl IntoIterator<Item = String>, max_size: usize) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > max_size {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(&line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+/// A socket to send datagrams to the agent.
+enum Transport {
+    Udp {
+        socket: UdpSocket,
+        addr: String,
+    },
+    #[cfg(unix)]
+    Unix {
+        socket: std::os::unix::net::UnixDatagram,
+        path: PathBuf,
+    },
+}
+
+impl Transport {
+    fn new(target: Target) -> io::Result<Self> {
+        match target {
+            Target::Udp(addr) => Ok(Self::Udp {
+                socket: UdpSocket::bind("0.0.0.0:0")?,
+                addr,
+            }),
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                // The agent may not be listening yet, in which case sending reconnects.
+                let _ = socket.connect(&path);
+                Ok(Self::Unix { socket, path })
+            }
+            #[cfg(not(unix))]
+            Target::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    fn max_datagram_size(&self) -> usize {
+        match self {
+            Self::Udp { .. } => MAX_UDP_DATAGRAM_SIZE,
+            #[cfg(unix)]
+            Self::Unix { .. } => MAX_UDS_DATAGRAM_SIZE,
+        }
+    }
+
+    /// Sends a datagram, dropping it if the agent is unavailable.
+    fn send(&self, datagram: &[u8]) {
+        match self {
+            Self::Udp { socket, addr } => {
+                let _ = socket.send_to(datagram, addr);
+            }
+            #[cfg(unix)]
+            Self::Unix { socket, path } => {
+                if socket.send(datagram).is_err() && socket.connect(path).is_ok() {
+                    let _ = socket.send(datagram);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::StandInAgent;
+
+    #[test]
+    fn lines_are_batched_up_to_the_max_size() {
+        let lines = ["a:1|c", "b:2|c", "c:3|c", "long_metric_name:4|c"].map(String::from);
+        assert_eq!(
+            batch(lines, 11),
+            ["a:1|c\nb:2|c", "c:3|c", "long_metric_name:4|c"]
+        );
+    }
+
+    #[test]
+    fn metrics_are_aggregated_until_flushed() {
+        let agent = StandInAgent::bind();
+        let aggregation = Aggregation::start(
+            Target::Udp(agent.addr()),
+            vec![String::from("service:payments")],
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+
+        let tags = || vec![String::from("method:card")];
+        aggregation.add("payments.captured", tags(), Value::Count(1));
+        aggregation.add("payments.captured", tags(), Value::Count(2));
+        let merchant_tags = |tags: [&str; 2]| tags.map(String::from).to_vec();
+        aggregation.add(
+            "payments.refunded",
+            merchant_tags(["merchant:m_42", "method:card"]),
+            Value::Count(1),
+        );
+        aggregation.add(
+            "payments.refunded",
+            merchant_tags(["method:card", "merchant:m_42"]),
+            Value::Count(1),
+        );
+        aggregation.add("payments.queue", vec![], Value::Gauge("3"));
+        aggregation.add("payments.queue", vec![], Value::Gauge("5"));
+        aggregation.add("payments.amount", tags(), Value::Histogram("1000"));
+        aggregation.flush();
+
+        let datagram = agent.recv();
+        let mut lines = datagram.lines().collect::<Vec<_>>();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            [
+                "payments.amount:1000|h|#method:card,service:payments",
+                "payments.captured:3|c|#method:card,service:payments",
+                "payments.queue:5|g|#service:payments",
+                "payments.refunded:2|c|#merchant:m_42,method:card,service:payments",
+            ]
+        );
+    }
+}
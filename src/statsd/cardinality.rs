@@ -0,0 +1,153 @@
+//! Limits on the number of distinct tag sets per metric.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+};
+
+/// The value that new tag values are replaced with once a metric reaches its limit.
+const OTHER: &str = "other";
+
+/// Limits the number of distinct tag sets per metric name, see
+/// [`Config::metrics_cardinality_limit`](crate::Config::metrics_cardinality_limit).
+///
+/// Once a metric has reached the limit, tag values it has not been seen with are replaced with
+/// `other`, while known values are kept, so that dashboards still show the most common ones.
+///
+/// Each metric has its own lock, so that only metrics with the same name contend.
+#[derive(Debug)]
+pub(super) struct CardinalityLimiter {
+    limit: usize,
+    metrics: RwLock<HashMap<String, Arc<Mutex<SeenTags>>>>,
+}
+
+/// The tags seen with a metric.
+#[derive(Debug, Default)]
+struct SeenTags {
+    /// The distinct tag sets, sorted to ignore the order of tags.
+    sets: HashSet<Vec<String>>,
+    /// The distinct values, by tag key.
+    values: HashMap<String, HashSet<String>>,
+    /// Whether a warning was logged for reaching the limit.
+    warned: bool,
+}
+
+impl CardinalityLimiter {
+    /// Creates a limiter, or `None` if the limit is `0`.
+    pub(super) fn new(limit: usize) -> Option<Self> {
+        (limit > 0).then(|| Self {
+            limit,
+            metrics: RwLock::default(),
+        })
+    }
+
+    /// Returns the tags to send for a metric, with new values replaced if over the limit, and
+    /// whether any were replaced.
+    pub(super) fn limit(&self, metric: &str, mut tags: Vec<String>) -> (Vec<String>, bool) {
+        let seen = self.seen(metric);
+        let mut seen = seen.lock().unwrap_or_else(|err| err.into_inner());
+
+        let mut set = tags.clone();
+        set.sort_unstable();
+        if seen.sets.contains(&set) {
+            return (tags, false);
+        }
+
+        if seen.sets.len() < self.limit {
+            for tag in &tags {
+                let (key, value) = split_tag(tag);
+                seen.values
+                    .entry(key.to_string())
+                    .or_default()
+                    .insert(value.to_string());
+            }
+            seen.sets.insert(set);
+            return (tags, false);
+        }
+
+        let mut limited = false;
+        for tag in &mut tags {
+            let (key, value) = split_tag(tag);
+            let known = seen
+                .values
+                .get(key)
+                .is_some_and(|values| values.contains(value));
+            if !known {
+                *tag = if tag.contains(':') {
+                    format!("{key}:{OTHER}")
+                } else {
+                    OTHER.to_string()
+                };
+                limited = true;
+            }
+        }
+
+        if limited && !seen.warned {
+            seen.warned = true;
+            tracing::warn!(
+                metric,
+                limit = self.limit,
+                "metric reached its limit of distinct tag sets, new tag values are reported as `other`"
+            );
+        }
+        (tags, limited)
+    }
+
+    /// Returns the tags seen with a metric, only taking the write lock for new metrics.
+    fn seen(&self, metric: &str) -> Arc<Mutex<SeenTags>> {
+        let metrics = self.metrics.read().unwrap_or_else(|err| err.into_inner());
+        if let Some(seen) = metrics.get(metric) {
+            return seen.clone();
+        }
+        drop(metrics);
+
+        let mut metrics = self.metrics.write().unwrap_or_else(|err| err.into_inner());
+        metrics.entry(metric.to_string()).or_default().clone()
+    }
+}
+
+/// Splits a `key:value` tag, with the whole tag as the key of a lone value.
+fn split_tag(tag: &str) -> (&str, &str) {
+    tag.split_once(':').unwrap_or((tag, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn new_values_are_collapsed_over_the_limit() {
+        let limiter = CardinalityLimiter::new(2).unwrap();
+        let limit = |metric, tags: &[&str]| limiter.limit(metric, self::tags(tags));
+
+        assert_eq!(
+            limit("payments", &["merchant:a", "method:card"]),
+            (tags(&["merchant:a", "method:card"]), false)
+        );
+        assert_eq!(
+            limit("payments", &["method:konbini", "merchant:b"]),
+            (tags(&["method:konbini", "merchant:b"]), false)
+        );
+        assert_eq!(
+            limit("payments", &["merchant:c", "method:card"]),
+            (tags(&["merchant:other", "method:card"]), true)
+        );
+        assert_eq!(
+            limit("payments", &["method:card", "merchant:a"]),
+            (tags(&["method:card", "merchant:a"]), false)
+        );
+        assert_eq!(
+            limit("refunds", &["merchant:c"]),
+            (tags(&["merchant:c"]), false)
+        );
+    }
+
+    #[test]
+    fn no_limiter_for_zero() {
+        assert!(CardinalityLimiter::new(0).is_none());
+    }
+}
@@ -0,0 +1,306 @@
+//! Typed tags, normalized like the Datadog agent does.
+
+use std::fmt::{Display, Formatter};
+#[cfg(debug_assertions)]
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, Mutex},
+};
+use tracing::Span;
+
+/// The maximum length of a tag in characters, beyond which the agent truncates it.
+const MAX_TAG_LENGTH: usize = 200;
+
+/// A `key:value` tag for metrics or spans, normalized following the Datadog agent's rules.
+///
+/// Tags are lowercased, start with a letter, and contain only alphanumerics, `_`, `-`, `:`, `.`
+/// and `/`, with other characters replaced by `_`. They are truncated to 200 characters and never
+/// end with a colon. Tags that are empty once normalized, like `tag!("42")`, are not sent. In
+/// debug builds, a warning is logged the first time normalization changes each distinct tag, so
+/// that call sites can be fixed.
+///
+/// Tags can be passed to any [`StatsD`](super::StatsD) method, and recorded on spans with
+/// [`Tag::record`].
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::{statsd::StatsD, tag};
+///
+/// let merchant_id = "Merchant 42";
+/// let merchant = tag!("merchant", merchant_id);
+/// assert_eq!(merchant.as_str(), "merchant:merchant_42");
+///
+/// # let statsd = StatsD::disabled();
+/// let _ = statsd.incr("payments.captured", [&merchant, &tag!("method", "card")]);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tag(String);
+
+impl Tag {
+    /// Creates a `key:value` tag, see [`tag!`](crate::tag).
+    pub fn new(key: impl Display, value: impl Display) -> Self {
+        Self::parse(&format!("{key}:{value}"))
+    }
+
+    /// Creates a tag from its raw form, either `key:value` or a lone value.
+    pub fn parse(raw: &str) -> Self {
+        let tag = normalize(raw);
+        #[cfg(debug_assertions)]
+        if tag != raw && first_normalization(raw) {
+            if tag.is_empty() {
+                tracing::warn!(tag = raw, "Datadog tag is empty once normalized");
+            } else {
+                tracing::warn!(tag = raw, normalized = tag, "Datadog tag was normalized");
+            }
+        }
+        Self(tag)
+    }
+
+    /// Returns the tag as sent to Datadog.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the key of a `key:value` tag, or the whole tag otherwise.
+    pub fn key(&self) -> &str {
+        self.0.split_once(':').map_or(&self.0, |(key, _)| key)
+    }
+
+    /// Returns the value of a `key:value` tag, if any.
+    pub fn value(&self) -> Option<&str> {
+        self.0.split_once(':').map(|(_, value)| value)
+    }
+
+    /// Records the tag on a span, as the field named by its key.
+    ///
+    /// Like [`Span::record`], this does nothing unless the span declares the field, e.g. with
+    /// `tracing::field::Empty`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use komoju_datadog::tag;
+    ///
+    /// let span = tracing::info_span!("capture", merchant = tracing::field::Empty);
+    /// tag!("merchant", "m_42").record(&span);
+    /// ```
+    pub fn record(&self, span: &Span) {
+        span.record(self.key(), self.value().unwrap_or_default());
+    }
+}
+
+impl AsRef<str> for Tag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A list of [`Tag`]s, e.g. to share between metrics.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::{statsd::{StatsD, Tags}, tag};
+///
+/// let tags = Tags::from([tag!("merchant", "m_42"), tag!("method", "card")]);
+///
+/// # let statsd = StatsD::disabled();
+/// let _ = statsd.incr("payments.captured", &tags);
+/// let _ = statsd.histogram("payments.amount", "1000", &tags);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tags(Vec<Tag>);
+
+impl Tags {
+    /// Creates an empty list of tags.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tag.
+    pub fn push(&mut self, tag: Tag) {
+        self.0.push(tag);
+    }
+
+    /// Adds a tag, returning the list for chaining.
+    pub fn with(mut self, tag: Tag) -> Self {
+        self.push(tag);
+        self
+    }
+
+    /// Returns an iterator over the tags.
+    pub fn iter(&self) -> std::slice::Iter<'_, Tag> {
+        self.0.iter()
+    }
+
+    /// Records every tag on a span, see [`Tag::record`].
+    pub fn record(&self, span: &Span) {
+        for tag in self {
+            tag.record(span);
+        }
+    }
+}
+
+impl<const N: usize> From<[Tag; N]> for Tags {
+    fn from(tags: [Tag; N]) -> Self {
+        Self(tags.into())
+    }
+}
+
+impl From<Vec<Tag>> for Tags {
+    fn from(tags: Vec<Tag>) -> Self {
+        Self(tags)
+    }
+}
+
+impl FromIterator<Tag> for Tags {
+    fn from_iter<I: IntoIterator<Item = Tag>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Extend<Tag> for Tags {
+    fn extend<I: IntoIterator<Item = Tag>>(&mut self, iter: I) {
+        self.0.extend(iter);
+    }
+}
+
+impl<'a> IntoIterator for &'a Tags {
+    type Item = &'a Tag;
+    type IntoIter = std::slice::Iter<'a, Tag>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl IntoIterator for Tags {
+    type Item = Tag;
+    type IntoIter = std::vec::IntoIter<Tag>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Creates a [`Tag`](crate::statsd::Tag), either from a key and a value, or from a raw tag.
+///
+/// Both keys and values can be anything that implements [`Display`].
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::tag;
+///
+/// assert_eq!(tag!("merchant", 42).as_str(), "merchant:42");
+/// assert_eq!(tag!("canary").as_str(), "canary");
+/// ```
+#[macro_export]
+macro_rules! tag {
+    ($key:expr, $value:expr $(,)?) => {
+        $crate::statsd::Tag::new($key, $value)
+    };
+    ($tag:expr $(,)?) => {
+        $crate::statsd::Tag::parse(::std::convert::AsRef::<str>::as_ref(&$tag))
+    };
+}
+
+/// Normalizes a tag like the Datadog agent does, see [`Tag`].
+fn normalize(raw: &str) -> String {
+    let mut tag = String::with_capacity(raw.len());
+    let mut len = 0;
+    for c in raw.chars().flat_map(char::to_lowercase) {
+        if len == MAX_TAG_LENGTH {
+            break;
+        }
+        if tag.is_empty() && !c.is_alphabetic() {
+            continue;
+        }
+        let c = if c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '/') {
+            c
+        } else {
+            '_'
+        };
+        if c == '_' && tag.ends_with('_') {
+            continue;
+        }
+        tag.push(c);
+        len += 1;
+    }
+
+    let trimmed = tag.trim_end_matches(['_', ':']).len();
+    tag.truncate(trimmed);
+    tag
+}
+
+/// Returns whether normalization changed `raw` for the first time, so that it is only warned about
+/// once.
+#[cfg(debug_assertions)]
+fn first_normalization(raw: &str) -> bool {
+    static WARNED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+    WARNED
+        .lock()
+        .expect("normalized tags lock poisoned")
+        .insert(raw.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(tag!("merchant", "m_42").as_str(), "merchant:m_42");
+        assert_eq!(tag!("Merchant", "M 42").as_str(), "merchant:m_42");
+        assert_eq!(
+            tag!("_route", "/v1/payments").as_str(),
+            "route:/v1/payments"
+        );
+        assert_eq!(tag!("status", "").as_str(), "status");
+        assert_eq!(tag!("note", "a  +  b!").as_str(), "note:a_b");
+        assert_eq!(tag!("city", "Zürich").as_str(), "city:zürich");
+        assert_eq!(tag!("42").as_str(), "");
+
+        let long = tag!("id", "x".repeat(300));
+        assert_eq!(long.as_str().chars().count(), MAX_TAG_LENGTH);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn normalization_is_warned_about_once() {
+        assert!(first_normalization("Warned Once"));
+        assert!(!first_normalization("Warned Once"));
+        assert!(first_normalization("Other Tag"));
+    }
+
+    #[test]
+    fn tag_key_and_value() {
+        let tag = tag!("http.route", "/a:b");
+        assert_eq!(tag.key(), "http.route");
+        assert_eq!(tag.value(), Some("/a:b"));
+
+        let tag = tag!("canary");
+        assert_eq!(tag.key(), "canary");
+        assert_eq!(tag.value(), None);
+    }
+
+    #[test]
+    fn tags_work_with_statsd() {
+        let statsd = super::super::StatsD::disabled();
+        let tags = Tags::from([tag!("merchant", "m_42")]).with(tag!("method", "card"));
+        assert!(statsd.incr("payments.captured", &tags).is_ok());
+        assert!(statsd.incr("payments.captured", [&tag!("a", 1)]).is_ok());
+        assert_eq!(
+            tags.iter().map(Tag::as_str).collect::<Vec<_>>(),
+            ["merchant:m_42", "method:card"]
+        );
+    }
+}
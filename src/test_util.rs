@@ -0,0 +1,143 @@
+//! Helpers for tests.
+
+// The span helpers are only used by the web framework integrations.
+#![cfg_attr(not(any(feature = "actix", feature = "tower")), allow(dead_code))]
+
+use crate::{config::ConfigBuilder, statsd::StatsD};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{
+    Subscriber,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+};
+use tracing_subscriber::{
+    layer::{Context as LayerContext, SubscriberExt},
+    registry::LookupSpan,
+};
+
+/// Subscriber layer that collects all recorded span fields by name, and the name of the last span
+/// that a span was linked to as `follows_from`.
+#[derive(Clone, Default)]
+pub(crate) struct FieldRecorder(Arc<Mutex<HashMap<String, String>>>);
+
+impl FieldRecorder {
+    pub(crate) fn get(&self, field: &str) -> Option<String> {
+        self.0.lock().unwrap().get(field).cloned()
+    }
+}
+
+impl Visit for FieldRecorder {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _ = self
+            .0
+            .lock()
+            .unwrap()
+            .insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = self
+            .0
+            .lock()
+            .unwrap()
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for FieldRecorder
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: LayerContext<'_, S>) {
+        attrs.record(&mut self.clone());
+    }
+
+    fn on_record(&self, _id: &Id, values: &Record<'_>, _ctx: LayerContext<'_, S>) {
+        values.record(&mut self.clone());
+    }
+
+    fn on_follows_from(&self, _id: &Id, follows: &Id, ctx: LayerContext<'_, S>) {
+        if let Some(follows) = ctx.span(follows) {
+            let _ = self
+                .0
+                .lock()
+                .unwrap()
+                .insert(String::from("follows_from"), follows.name().to_string());
+        }
+    }
+}
+
+/// Runs `f` with a subscriber that records span fields.
+pub(crate) fn with_recorder<F: Future>(f: F) -> (FieldRecorder, F::Output) {
+    let recorder = FieldRecorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+    let output = tracing::subscriber::with_default(subscriber, || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    });
+    (recorder, output)
+}
+
+/// A local stand-in for the Datadog agent, receiving StatsD datagrams.
+pub(crate) struct StandInAgent(UdpSocket);
+
+impl StandInAgent {
+    /// Binds the agent to a free local port.
+    pub(crate) fn bind() -> Self {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Self(socket)
+    }
+
+    /// Returns the address to send metrics to.
+    pub(crate) fn addr(&self) -> String {
+        self.0.local_addr().unwrap().to_string()
+    }
+
+    /// Receives the next datagram, panicking if none arrives in time.
+    pub(crate) fn recv(&self) -> String {
+        let mut buf = [0; 8192];
+        let len = self.0.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    /// Receives datagrams until none arrive for a moment.
+    #[cfg(feature = "tower")]
+    pub(crate) fn drain(&self) -> Vec<String> {
+        self.0
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buf = [0; 8192];
+        let mut datagrams = Vec::new();
+        while let Ok(len) = self.0.recv(&mut buf) {
+            datagrams.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+        }
+        datagrams
+    }
+}
+
+/// Starts a stand-in agent, returning it with a client built from `config` that sends to it.
+pub(crate) fn stand_in_agent(config: ConfigBuilder) -> (StandInAgent, StatsD) {
+    let agent = StandInAgent::bind();
+    let config = config.metrics_agent_url(agent.addr()).build().unwrap();
+    (agent, StatsD::new(&config))
+}
+
+/// Polls the next frame from a body.
+#[cfg(feature = "tower")]
+pub(crate) async fn next_frame<B: http_body::Body + Unpin>(
+    body: &mut B,
+) -> Option<Result<http_body::Frame<B::Data>, B::Error>> {
+    std::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_frame(cx)).await
+}
@@ -0,0 +1,565 @@
+//! gRPC server and client tracing for `tonic`.
+//!
+//! Both layers work on the HTTP level, so they can be used with `tonic`'s server and channels as
+//! well as any other tower-based gRPC stack.
+
+use bytes::Buf;
+use http::{HeaderMap, Request, Response, header};
+use http_body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+use tracing::{Span, field::Empty};
+use tracing_datadog::{
+    context::{TraceContextExt, TracingContextExt},
+    http::W3CTraceContextHeaders,
+};
+
+/// Splits a gRPC request path like `/pkg.Service/Method` into service and method.
+fn rpc_service_and_method(path: &str) -> (&str, &str) {
+    let path = path.trim_start_matches('/');
+    path.split_once('/').unwrap_or((path, ""))
+}
+
+/// The side of a gRPC request that a span is created for.
+#[derive(Clone, Copy, Debug)]
+enum SpanKind {
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    /// Returns the operation name of spans of this kind.
+    fn operation(self) -> &'static str {
+        match self {
+            Self::Server => "grpc.server",
+            Self::Client => "grpc.client",
+        }
+    }
+
+    /// Returns the `span.kind` tag of spans of this kind.
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Server => "server",
+            Self::Client => "client",
+        }
+    }
+}
+
+/// Creates a span for a gRPC request, server or client side.
+fn make_span_from_request<B>(req: &Request<B>, kind: SpanKind) -> Span {
+    let path = req.uri().path();
+    let (service, method) = rpc_service_and_method(path);
+    tracing::info_span!(
+        "gRPC request",
+        operation = kind.operation(),
+        resource = path,
+        rpc.system = "grpc",
+        rpc.service = service,
+        rpc.method = method,
+        rpc.grpc.status_code = Empty,
+        rpc.grpc.status_name = Empty,
+        rpc.request.bytes = Empty,
+        rpc.response.bytes = Empty,
+        server.address = req.uri().host(),
+        error.type = Empty,
+        error.message = Empty,
+        error.stack = Empty,
+        span.kind = kind.as_str(),
+        span.type = "rpc",
+    )
+}
+
+/// Records a gRPC status on a span, marking it as an error for non-OK codes.
+fn record_status(span: &Span, code: Code, message: &str) {
+    span.record("rpc.grpc.status_code", code as i32);
+    span.record("rpc.grpc.status_name", format!("{code:?}"));
+    if code != Code::Ok {
+        span.record("error.type", format!("grpc.{code:?}"));
+        span.record(
+            "error.message",
+            if message.is_empty() {
+                code.description()
+            } else {
+                message
+            },
+        );
+    }
+}
+
+/// Records the gRPC status from response headers or trailers, if present.
+///
+/// Returns whether a status was found.
+fn record_status_from_headers(span: &Span, headers: &HeaderMap) -> bool {
+    match Status::from_header_map(headers) {
+        Some(status) => {
+            record_status(span, status.code(), status.message());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Tower layer to trace gRPC server requests.
+///
+/// Creates a `grpc.server` span for every request, continuing the trace from the request
+/// metadata. The span stays open until the response stream has finished, and records the gRPC
+/// status from the response headers or trailers. Non-OK statuses are marked as errors.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::tonic::GrpcServerTraceLayer;
+/// use tower::ServiceBuilder;
+///
+/// // Pass this to `tonic::transport::Server::builder().layer(...)`.
+/// let layer = ServiceBuilder::new().layer(GrpcServerTraceLayer);
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcServerTraceLayer;
+
+impl<S> Layer<S> for GrpcServerTraceLayer {
+    type Service = GrpcServerTraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcServerTraceService { inner }
+    }
+}
+
+/// Middleware `Service` that creates spans for every gRPC server request.
+#[derive(Clone, Debug)]
+pub struct GrpcServerTraceService<S> {
+    inner: S,
+}
+
+impl<S, B, B2> Service<Request<B>> for GrpcServerTraceService<S>
+where
+    S: Service<Request<B>, Response = Response<B2>>,
+    S::Error: Error + 'static,
+{
+    type Response = Response<GrpcBody<B2>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let span = make_span_from_request(&req, SpanKind::Server);
+        span.set_context(
+            req.headers()
+                .extract_trace_context::<W3CTraceContextHeaders>(),
+        );
+        record_request_length(&span, req.headers());
+
+        let inner = {
+            let _guard = span.enter();
+            self.inner.call(req)
+        };
+        ResponseFuture { inner, span }
+    }
+}
+
+/// Tower layer to trace gRPC client requests.
+///
+/// Creates a `grpc.client` span for every request, and propagates the trace through the request
+/// metadata. The span stays open until the response stream has finished, and records the gRPC
+/// status from the response headers or trailers. Non-OK statuses are marked as errors.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::tonic::GrpcClientTraceLayer;
+/// use tower::ServiceBuilder;
+///
+/// // Wrap a `tonic::transport::Channel` with this before passing it to a generated client.
+/// let layer = ServiceBuilder::new().layer(GrpcClientTraceLayer);
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcClientTraceLayer;
+
+impl<S> Layer<S> for GrpcClientTraceLayer {
+    type Service = GrpcClientTraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcClientTraceService { inner }
+    }
+}
+
+/// Middleware `Service` that creates spans for every gRPC client request.
+#[derive(Clone, Debug)]
+pub struct GrpcClientTraceService<S> {
+    inner: S,
+}
+
+impl<S, B, B2> Service<Request<B>> for GrpcClientTraceService<S>
+where
+    S: Service<Request<B>, Response = Response<B2>>,
+    S::Error: Error + 'static,
+{
+    type Response = Response<GrpcBody<B2>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let span = make_span_from_request(&req, SpanKind::Client);
+        record_request_length(&span, req.headers());
+        req.headers_mut()
+            .inject_trace_context::<W3CTraceContextHeaders>(span.get_context());
+
+        let inner = {
+            let _guard = span.enter();
+            self.inner.call(req)
+        };
+        ResponseFuture { inner, span }
+    }
+}
+
+/// Records the request length, if known.
+fn record_request_length(span: &Span, headers: &HeaderMap) {
+    if let Some(length) = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok()?.parse::<u64>().ok())
+    {
+        span.record("rpc.request.bytes", length);
+    }
+}
+
+pin_project! {
+    /// Response future of the gRPC tracing services.
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    E: Error + 'static,
+{
+    type Output = Result<Response<GrpcBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = {
+            let _guard = this.span.enter();
+            futures_util::ready!(this.inner.poll(cx))
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                crate::tracing::record_error(this.span, &err);
+                return Poll::Ready(Err(err));
+            }
+        };
+
+        // Trailers-only responses, including most errors, carry the status in the headers.
+        let state = BodyState {
+            span: this.span.clone(),
+            bytes: 0,
+            status_recorded: record_status_from_headers(this.span, response.headers()),
+        };
+
+        Poll::Ready(Ok(response.map(|inner| GrpcBody {
+            inner,
+            state: Some(state),
+        })))
+    }
+}
+
+pin_project! {
+    /// Response body of the gRPC tracing services.
+    ///
+    /// Keeps the span open until the response stream has finished, and records the gRPC status
+    /// from the trailers.
+    pub struct GrpcBody<B> {
+        #[pin]
+        inner: B,
+        state: Option<BodyState>,
+    }
+
+    impl<B> PinnedDrop for GrpcBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(state) = this.project().state.take() {
+                state.finish(Some(Code::Cancelled));
+            }
+        }
+    }
+}
+
+/// Tracking state for a [`GrpcBody`] that is still being streamed.
+struct BodyState {
+    span: Span,
+    bytes: u64,
+    /// Whether the gRPC status has been recorded already.
+    status_recorded: bool,
+}
+
+impl BodyState {
+    /// Records the final tags on the span, using `fallback` if no status was received.
+    fn finish(self, fallback: Option<Code>) {
+        crate::http::touch_span(&self.span);
+        self.span.record("rpc.response.bytes", self.bytes);
+        if let (false, Some(code)) = (self.status_recorded, fallback) {
+            record_status(&self.span, code, "");
+        }
+    }
+}
+
+impl<B> Body for GrpcBody<B>
+where
+    B: Body,
+    B::Error: std::fmt::Display,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let Some(state) = this.state.as_mut() else {
+            return this.inner.poll_frame(cx);
+        };
+
+        let result = {
+            let _guard = state.span.enter();
+            futures_util::ready!(this.inner.as_mut().poll_frame(cx))
+        };
+
+        let finished = match &result {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    state.bytes += data.remaining() as u64;
+                }
+                if let Some(trailers) = frame.trailers_ref() {
+                    state.status_recorded |= record_status_from_headers(&state.span, trailers);
+                }
+                this.inner.is_end_stream()
+            }
+            Some(Err(err)) => {
+                record_status(&state.span, Code::Internal, &err.to_string());
+                state.status_recorded = true;
+                true
+            }
+            None => true,
+        };
+
+        if finished {
+            if let Some(state) = this.state.take() {
+                // A stream ending without a status is a protocol violation.
+                state.finish(Some(Code::Unknown));
+            }
+        }
+
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{next_frame, with_recorder};
+    use bytes::Bytes;
+    use std::{
+        collections::VecDeque,
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+    use tower::{ServiceExt, service_fn};
+    use tracing_datadog::DatadogTraceLayer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn rpc_service_and_method_from_path() {
+        assert_eq!(
+            rpc_service_and_method("/komoju.payments.v1.Payments/Capture"),
+            ("komoju.payments.v1.Payments", "Capture")
+        );
+        assert_eq!(rpc_service_and_method("/Unknown"), ("Unknown", ""));
+    }
+
+    /// A response body made of the given frames.
+    struct Frames(VecDeque<Frame<Bytes>>);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    /// Returns gRPC status metadata.
+    fn status(code: Code, message: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-status", (code as i32).into());
+        if !message.is_empty() {
+            headers.insert("grpc-message", message.parse().unwrap());
+        }
+        headers
+    }
+
+    /// Sends a request through `service` and reads the whole response body.
+    async fn call<S, B>(service: S) -> Result<(), S::Error>
+    where
+        S: Service<Request<()>, Response = Response<GrpcBody<B>>>,
+        B: Body + Unpin,
+        B::Error: std::fmt::Display,
+    {
+        let request = Request::post("/komoju.payments.v1.Payments/Capture")
+            .body(())
+            .unwrap();
+        let mut body = service.oneshot(request).await?.into_body();
+        while next_frame(&mut body).await.is_some() {}
+        Ok(())
+    }
+
+    #[test]
+    fn server_records_status_from_headers() {
+        let (recorder, _) = with_recorder(call(GrpcServerTraceLayer.layer(service_fn(
+            |_req: Request<()>| async {
+                // A trailers-only response.
+                let mut response = Response::new(Frames(VecDeque::new()));
+                *response.headers_mut() = status(Code::NotFound, "merchant not found");
+                Ok::<_, Infallible>(response)
+            },
+        ))));
+
+        assert_eq!(recorder.get("operation").as_deref(), Some("grpc.server"));
+        assert_eq!(recorder.get("span.kind").as_deref(), Some("server"));
+        assert_eq!(recorder.get("rpc.grpc.status_code").as_deref(), Some("5"));
+        assert_eq!(
+            recorder.get("rpc.grpc.status_name").as_deref(),
+            Some("NotFound")
+        );
+        assert_eq!(recorder.get("error.type").as_deref(), Some("grpc.NotFound"));
+        assert_eq!(
+            recorder.get("error.message").as_deref(),
+            Some("merchant not found")
+        );
+    }
+
+    #[test]
+    fn client_records_status_from_trailers() {
+        let (recorder, _) = with_recorder(call(GrpcClientTraceLayer.layer(service_fn(
+            |_req: Request<()>| async {
+                let frames = [
+                    Frame::data(Bytes::from_static(b"hello")),
+                    Frame::trailers(status(Code::Ok, "")),
+                ];
+                Ok::<_, Infallible>(Response::new(Frames(frames.into())))
+            },
+        ))));
+
+        assert_eq!(recorder.get("operation").as_deref(), Some("grpc.client"));
+        assert_eq!(recorder.get("span.kind").as_deref(), Some("client"));
+        assert_eq!(recorder.get("rpc.grpc.status_code").as_deref(), Some("0"));
+        assert_eq!(recorder.get("rpc.grpc.status_name").as_deref(), Some("Ok"));
+        assert_eq!(recorder.get("rpc.response.bytes").as_deref(), Some("5"));
+        assert_eq!(recorder.get("error.type"), None);
+    }
+
+    #[test]
+    fn error_status_from_trailers_is_marked() {
+        let (recorder, _) = with_recorder(call(GrpcServerTraceLayer.layer(service_fn(
+            |_req: Request<()>| async {
+                let frames = [Frame::trailers(status(Code::Unavailable, ""))];
+                Ok::<_, Infallible>(Response::new(Frames(frames.into())))
+            },
+        ))));
+
+        assert_eq!(recorder.get("rpc.grpc.status_code").as_deref(), Some("14"));
+        assert_eq!(
+            recorder.get("error.type").as_deref(),
+            Some("grpc.Unavailable")
+        );
+        assert_eq!(
+            recorder.get("error.message").as_deref(),
+            Some(Code::Unavailable.description())
+        );
+    }
+
+    #[test]
+    fn transport_errors_are_recorded_without_status() {
+        let (recorder, result) = with_recorder(call(GrpcClientTraceLayer.layer(service_fn(
+            |_req: Request<()>| async {
+                Err::<Response<Frames>, _>(std::io::Error::other("connection refused"))
+            },
+        ))));
+
+        assert!(result.is_err());
+        assert_eq!(recorder.get("rpc.grpc.status_code"), None);
+        assert_eq!(
+            recorder.get("error.message").as_deref(),
+            Some("connection refused")
+        );
+    }
+
+    #[test]
+    fn trace_context_is_propagated_from_client_to_server() {
+        let layer = DatadogTraceLayer::builder()
+            .service("payments")
+            .env("test")
+            .version("1.0.0")
+            .agent_address("127.0.0.1:0")
+            .build()
+            .unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let server_context = Arc::new(Mutex::new(None));
+            let server = GrpcServerTraceLayer.layer(service_fn({
+                let server_context = server_context.clone();
+                move |req: Request<()>| {
+                    assert!(req.headers().contains_key("traceparent"));
+                    *server_context.lock().unwrap() = Some(Span::current().get_context());
+                    async { Ok::<_, Infallible>(Response::new(Frames(VecDeque::new()))) }
+                }
+            }));
+            let client = GrpcClientTraceLayer.layer(server);
+
+            let caller = tracing::info_span!("caller");
+            let _guard = caller.enter();
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let _ = runtime.block_on(call(client));
+
+            let server_context = server_context.lock().unwrap().unwrap();
+            assert_ne!(server_context.trace_id, 0);
+            assert_eq!(server_context.trace_id, caller.get_context().trace_id);
+        });
+    }
+}
@@ -2,18 +2,27 @@
 //!
 //! Like Rust's `tracing`, this handles both logs and trace spans.
 //!
-//! Logs are emitted to stdout, in a format that's dependent on the environment (human-readable
-//! for `development`, JSON for anything else).
+//! Logs are emitted to stdout, in a format that's dependent on the config (human-readable with
+//! [`pretty_logs`](crate::Config::pretty_logs), JSON with
+//! [`logs_injection`](crate::Config::logs_injection)).
 //!
-//! Traces are emitted to the Datadog agent.
+//! Traces are emitted to the Datadog agent, or to Datadog's intake in
+//! [agentless mode](crate::Config::api_key).
 
 #[cfg(all(feature = "aws_ecs", feature = "gcp_gke"))]
 compile_error!(
     "Features 'aws_ecs' and 'gcp_gke' are mutually exclusive and cannot be enabled together"
 );
 
+mod sampling;
+
+use crate::config::AgentAddress;
+use std::{any::type_name, error::Error, fmt::Write};
+use tracing::{Span, Subscriber};
 use tracing_datadog::DatadogTraceLayer;
-use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+};
 
 /// Tracing instrumentation. Should be initialized exactly once to install the global handlers.
 ///
@@ -31,44 +40,58 @@ use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::Subscr
 /// }
 /// ```
 #[allow(clippy::needless_doctest_main)]
-pub struct Tracer;
+pub struct Tracer {
+    /// The layer shipping logs in agentless mode.
+    #[cfg(feature = "agentless")]
+    logs: Option<crate::agentless::LogLayer>,
+}
 
 impl Tracer {
     /// Initializes tracing instrumentation.
+    ///
+    /// Logs the effective configuration once installed, see
+    /// [`Config::report`](crate::Config::report).
+    ///
+    /// Traces for a Unix socket agent URL are relayed through a background thread.
+    ///
+    /// In [agentless mode](crate::Config::api_key), traces are relayed to Datadog's intake through
+    /// a background thread instead of the agent, and logs are also shipped directly to Datadog by
+    /// another, unless [`logs_injection`](crate::Config::logs_injection) is disabled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the trace agent URL is a Unix socket on other platforms.
     pub fn new(config: &crate::Config) -> Self {
-        let dd_trace_layer = match &config.trace_agent_url {
-            Some(trace_agent_url) => {
-                #[cfg_attr(not(any(feature = "aws_ecs", feature = "gcp_gke")), allow(unused_mut))]
-                let mut builder = DatadogTraceLayer::builder()
-                    .service(&config.service)
-                    .env(&config.env)
-                    .version(&config.version)
-                    .agent_address(trace_agent_url)
-                    .enable_logs(config.env != "development");
-                #[cfg(feature = "aws_ecs")]
-                if let Some(container_id) = crate::aws::container_id() {
-                    builder = builder.container_id(container_id);
-                }
-                #[cfg(feature = "gcp_gke")]
-                if let Some(pod_uid) = crate::gcp::pod_uid() {
-                    builder = builder.container_id(pod_uid);
-                }
-                Some(
-                    builder
-                        .build()
-                        .expect("failed to build Datadog trace layer"),
-                )
-            }
-            _ => None,
-        };
-
-        tracing_subscriber::registry()
+        #[cfg(feature = "agentless")]
+        let intake = config
+            .api_key
+            .as_ref()
+            .map(|api_key| crate::agentless::Intake::new(api_key.expose(), &config.site));
+
+        #[cfg(feature = "agentless")]
+        let trace_address = trace_address(config, intake.clone());
+        #[cfg(not(feature = "agentless"))]
+        let trace_address = trace_address(config);
+        let dd_trace_layer = trace_address.map(|address| {
+            sampling::Sampled::new(trace_layer(config, &address), config.trace_sample_rate)
+        });
+
+        #[cfg(feature = "agentless")]
+        let logs = intake
+            .filter(|_| config.logs_injection)
+            .map(|intake| crate::agentless::LogLayer::new(config, intake));
+
+        let registry = tracing_subscriber::registry();
+        #[cfg(feature = "agentless")]
+        let registry = registry.with(logs.clone());
+
+        registry
             .with(
                 tracing_subscriber::EnvFilter::builder()
                     .with_default_directive(LevelFilter::INFO.into())
-                    .from_env_lossy(),
+                    .parse_lossy(&config.log_filter),
             )
-            .with(if config.env == "development" {
+            .with(if config.pretty_logs {
                 Some(tracing_subscriber::fmt::layer().pretty())
             } else {
                 None
@@ -76,6 +99,216 @@ impl Tracer {
             .with(dd_trace_layer)
             .init();
 
-        Self
+        tracing::info!(config = %config.report(), "Datadog configuration");
+
+        Self {
+            #[cfg(feature = "agentless")]
+            logs,
+        }
+    }
+
+    /// Submits logs buffered in [agentless mode](crate::Config::api_key), e.g. before a batch job
+    /// exits. Does nothing otherwise.
+    pub fn flush(&self) {
+        #[cfg(feature = "agentless")]
+        if let Some(logs) = &self.logs {
+            logs.flush();
+        }
+    }
+}
+
+/// Builds the layer exporting spans to the trace agent at `agent_address`.
+fn trace_layer<S>(config: &crate::Config, agent_address: &str) -> DatadogTraceLayer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut builder = DatadogTraceLayer::builder()
+        .service(&config.service)
+        .env(&config.env)
+        .version(&config.version)
+        .agent_address(agent_address)
+        .enable_logs(config.logs_injection);
+    for (key, value) in &config.tags {
+        builder = builder.default_tag(key.clone(), value);
+    }
+    if let Some(git_repository_url) = &config.git_repository_url {
+        builder = builder.default_tag("git.repository_url", git_repository_url);
+    }
+    if let Some(git_commit_sha) = &config.git_commit_sha {
+        builder = builder.default_tag("git.commit.sha", git_commit_sha);
+    }
+    #[cfg(feature = "aws_ecs")]
+    if let Some(container_id) = crate::aws::container_id() {
+        builder = builder.container_id(container_id);
+    }
+    #[cfg(feature = "gcp_gke")]
+    if let Some(pod_uid) = crate::gcp::pod_uid() {
+        builder = builder.container_id(pod_uid);
+    }
+    builder
+        .build()
+        .expect("failed to build Datadog trace layer")
+}
+
+/// Returns the `host:port` address to send traces to, if enabled.
+///
+/// In agentless mode, traces are relayed to the `intake`, regardless of the trace agent URL.
+fn trace_address(
+    config: &crate::Config,
+    #[cfg(feature = "agentless")] intake: Option<crate::agentless::Intake>,
+) -> Option<String> {
+    if !config.trace_enabled {
+        return None;
+    }
+    #[cfg(feature = "agentless")]
+    if let Some(intake) = intake {
+        let addr = crate::agentless::relay_traces(config, intake)
+            .expect("failed to relay traces to Datadog intake");
+        return Some(addr.to_string());
+    }
+    config.trace_agent_url.as_deref().map(agent_address)
+}
+
+/// Returns the `host:port` address to send traces to, relaying them for Unix sockets.
+fn agent_address(trace_agent_url: &str) -> String {
+    match AgentAddress::parse(trace_agent_url) {
+        #[cfg(unix)]
+        Ok(AgentAddress::Unix(path) | AgentAddress::Unixstream(path)) => {
+            crate::uds::relay_tcp_to_stream(path)
+                .expect("failed to relay traces to trace agent socket")
+                .to_string()
+        }
+        #[cfg(not(unix))]
+        Ok(AgentAddress::Unix(_) | AgentAddress::Unixstream(_)) => {
+            panic!("Unix sockets are not supported on this platform")
+        }
+        _ => trace_agent_url.to_string(),
+    }
+}
+
+/// Records an error on a span, using Datadog's error tags:
+///
+/// - `error.type`, the type name of the error
+/// - `error.message`, the error's display representation
+/// - `error.stack`, the error's full chain of sources
+///
+/// Like all `tracing` fields, these have to be declared when creating the span, e.g. as
+/// [`Empty`](tracing::field::Empty), to be recorded.
+///
+/// # Examples
+///
+/// ```
+/// use komoju_datadog::tracing::record_error;
+/// use tracing::{field::Empty, info_span};
+///
+/// let span = info_span!("parse", error.type = Empty, error.message = Empty, error.stack = Empty);
+///
+/// if let Err(err) = "forty-two".parse::<u64>() {
+///     record_error(&span, &err);
+/// }
+/// ```
+pub fn record_error<E>(span: &Span, error: &E)
+where
+    E: Error + ?Sized,
+{
+    span.record("error.type", type_name::<E>());
+    span.record("error.message", error.to_string());
+    span.record("error.stack", error_stack(error));
+}
+
+/// Renders an error and its chain of sources, one per line.
+fn error_stack<E>(error: &E) -> String
+where
+    E: Error + ?Sized,
+{
+    let mut stack = error.to_string();
+    let mut source = error.source();
+    if source.is_some() {
+        stack.push_str("\n\nCaused by:");
+    }
+    let mut index = 0;
+    while let Some(err) = source {
+        let _ = write!(stack, "\n    {index}: {err}");
+        source = err.source();
+        index += 1;
+    }
+    stack
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::{Display, Formatter};
+
+    #[derive(Debug)]
+    struct Outer(std::io::Error);
+
+    impl Display for Outer {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "failed to load settlement")
+        }
+    }
+
+    impl Error for Outer {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn error_stack_without_sources() {
+        assert_eq!(
+            error_stack(&std::fmt::Error),
+            "an error occurred when formatting an argument"
+        );
+    }
+
+    #[test]
+    fn error_stack_includes_sources() {
+        let error = Outer(std::io::Error::other("connection reset"));
+        assert_eq!(
+            error_stack(&error),
+            "failed to load settlement\n\nCaused by:\n    0: connection reset"
+        );
+    }
+
+    #[cfg(feature = "agentless")]
+    #[test]
+    fn agentless_traces_need_only_an_api_key() {
+        use crate::agentless::tests::stand_in_intake;
+
+        let config = crate::Config::builder()
+            .service("payments")
+            .api_key("test-api-key")
+            .build()
+            .unwrap();
+        assert_eq!(config.trace_agent_url, None);
+        assert!(config.trace_enabled);
+
+        let (intake, requests) = stand_in_intake();
+        let address = trace_address(&config, Some(intake)).unwrap();
+        // Kept until the end, as dropping the layer stops the exporter.
+        let dispatch = tracing::Dispatch::new(
+            tracing_subscriber::registry().with(trace_layer(&config, &address)),
+        );
+        tracing::dispatcher::with_default(&dispatch, || {
+            tracing::info_span!("capture").in_scope(|| {});
+        });
+
+        let request = requests
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            request.body["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["name"],
+            "capture"
+        );
+
+        let disabled = crate::Config::builder()
+            .api_key("test-api-key")
+            .trace_enabled(false)
+            .build()
+            .unwrap();
+        let (intake, _) = stand_in_intake();
+        assert_eq!(trace_address(&disabled, Some(intake)), None);
     }
 }
@@ -0,0 +1,160 @@
+//! Head-based sampling of traces, see [`Config::trace_sample_rate`](crate::Config::trace_sample_rate).
+
+use std::{
+    any::TypeId,
+    hash::{BuildHasher, RandomState},
+};
+use tracing::{
+    Event, Metadata, Subscriber,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+/// Wraps a layer exporting spans, so that it only exports a fraction of traces.
+///
+/// All spans are still passed to the inner layer, so that logs keep their trace context, but
+/// spans of unsampled traces are never closed for it, which keeps them from being exported.
+pub(crate) struct Sampled<L> {
+    inner: L,
+    rate: f64,
+}
+
+/// Whether the trace of a span is sampled, stored in the span's extensions.
+#[derive(Clone, Copy)]
+struct Decision(bool);
+
+impl<L> Sampled<L> {
+    /// Wraps `inner`, keeping a `rate` fraction of traces.
+    pub(crate) fn new(inner: L, rate: f64) -> Self {
+        Self { inner, rate }
+    }
+
+    /// Decides whether to keep a new trace.
+    fn sample(&self) -> bool {
+        self.rate >= 1.0 || (RandomState::new().hash_one(()) as f64) < self.rate * u64::MAX as f64
+    }
+}
+
+impl<S, L> Layer<S> for Sampled<L>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    L: Layer<S>,
+{
+    fn on_register_dispatch(&self, subscriber: &tracing::Dispatch) {
+        self.inner.on_register_dispatch(subscriber);
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let parent = span
+                .parent()
+                .and_then(|parent| parent.extensions().get::<Decision>().copied());
+            let decision = parent.unwrap_or_else(|| Decision(self.sample()));
+            span.extensions_mut().insert(decision);
+        }
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(id, values, ctx);
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, S>) {
+        self.inner.on_follows_from(id, follows, ctx);
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.event_enabled(event, ctx)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.inner.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let sampled = ctx
+            .span(&id)
+            .and_then(|span| span.extensions().get::<Decision>().copied())
+            .is_none_or(|Decision(sampled)| sampled);
+        if sampled {
+            self.inner.on_close(id, ctx);
+        }
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        self.inner.on_id_change(old, new, ctx);
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else {
+            // SAFETY: The inner layer upholds the same contract.
+            unsafe { self.inner.downcast_raw(id) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Counts the spans closed, i.e. exported.
+    #[derive(Clone, Default)]
+    struct Closed(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for Closed {
+        fn on_close(&self, _id: Id, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of spans closed for the inner layer, out of 100 traces of two spans.
+    fn closed_spans(rate: f64) -> usize {
+        let closed = Closed::default();
+        let subscriber = tracing_subscriber::registry().with(Sampled::new(closed.clone(), rate));
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::info_span!("request").in_scope(|| {
+                    tracing::info_span!("query").in_scope(|| {});
+                });
+            }
+        });
+        closed.0.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn traces_are_sampled_as_a_whole() {
+        assert_eq!(closed_spans(1.0), 200);
+        assert_eq!(closed_spans(0.0), 0);
+
+        let closed = closed_spans(0.5);
+        assert!(closed % 2 == 0 && closed > 0 && closed < 200, "{closed}");
+    }
+}
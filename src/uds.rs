@@ -0,0 +1,156 @@
+//! Unix domain socket transports.
+//!
+//! The trace exporter and DogStatsD client only support some transports natively, so these relay
+//! from a local socket they can use to the agent's Unix socket.
+
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// Relays TCP connections to a Unix stream socket, e.g. for HTTP requests to the trace agent.
+///
+/// Returns the local address to connect to instead of the socket.
+pub(crate) fn relay_tcp_to_stream(path: &Path) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let addr = listener.local_addr()?;
+    let path = path.to_path_buf();
+
+    thread::Builder::new()
+        .name("dd-uds-relay".into())
+        .spawn(move || {
+            for tcp in listener.incoming().flatten() {
+                let path = path.clone();
+                let _ = thread::Builder::new()
+                    .name("dd-uds-relay-conn".into())
+                    .spawn(move || relay_connection(tcp, &path));
+            }
+        })?;
+
+    Ok(addr)
+}
+
+/// Copies data both ways between a TCP connection and a new Unix socket connection.
+fn relay_connection(tcp: TcpStream, path: &Path) -> io::Result<()> {
+    let unix = UnixStream::connect(path)?;
+    let (mut tcp_read, mut unix_write) = (tcp.try_clone()?, unix.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut tcp_read, &mut unix_write);
+        let _ = unix_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut unix_read, mut tcp_write) = (unix, tcp);
+    let _ = io::copy(&mut unix_read, &mut tcp_write);
+    let _ = tcp_write.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// Relays UDP datagrams to a Unix stream socket, as DogStatsD expects them: each prefixed with
+/// its length as a 32-bit little-endian integer.
+///
+/// Datagrams are dropped while the socket is unavailable, like they would be over UDP.
+///
+/// Returns the local address to send datagrams to instead of the socket.
+pub(crate) fn relay_datagrams_to_stream(path: &Path) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(("127.0.0.1", 0))?;
+    let addr = socket.local_addr()?;
+    let path = path.to_path_buf();
+
+    thread::Builder::new()
+        .name("dd-dsd-relay".into())
+        .spawn(move || {
+            let mut relay = StreamRelay { path, stream: None };
+            let mut buf = vec![0; u16::MAX as usize];
+            while let Ok(len) = socket.recv(&mut buf) {
+                relay.send(&buf[..len]);
+            }
+        })?;
+
+    Ok(addr)
+}
+
+/// A lazily (re)connected Unix stream socket for DogStatsD datagrams.
+struct StreamRelay {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+}
+
+impl StreamRelay {
+    /// Sends a datagram, reconnecting once if the connection was lost.
+    fn send(&mut self, datagram: &[u8]) {
+        let mut frame = Vec::with_capacity(datagram.len() + 4);
+        frame.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        frame.extend_from_slice(datagram);
+
+        for _ in 0..2 {
+            if self.stream.is_none() {
+                self.stream = UnixStream::connect(&self.path).ok();
+            }
+            let Some(stream) = &mut self.stream else {
+                return;
+            };
+            if stream.write_all(&frame).is_ok() {
+                return;
+            }
+            self.stream = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, os::unix::net::UnixListener};
+
+    /// Returns a fresh socket path for a test.
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("komoju-dd-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn datagrams_are_length_prefixed() {
+        let path = socket_path("dsd.socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let addr = relay_datagrams_to_stream(&path).unwrap();
+
+        let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        client.send_to(b"payments.count:1|c", addr).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut datagram = vec![0; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut datagram).unwrap();
+        assert_eq!(datagram, b"payments.count:1|c");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn tcp_connections_are_relayed() {
+        let path = socket_path("apm.socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let addr = relay_tcp_to_stream(&path).unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 4];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        stream.write_all(b"pong").unwrap();
+
+        let mut response = [0; 4];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"pong");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}